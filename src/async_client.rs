@@ -0,0 +1,206 @@
+use super::*;
+
+/* Same surface as Client, for callers already running an async runtime.
+ * Signing and authentication tokens are shared with Client through Signature.
+ */
+pub struct AsyncClient {
+  signature: Signature,
+  api_url: String,
+  http: reqwest::Client,
+}
+
+impl AsyncClient {
+  /* Decrypting the key takes a while and blocks, prefer load from async code. */
+  pub fn new(config: Config, daily_passphrase: &str) -> Result<AsyncClient> {
+    let api_url = config.api_url(None)?;
+    let http = config.network().reqwest_client(&api_url, &Default::default())?;
+    let signature = Signature::load(config, daily_passphrase)?;
+    Ok(AsyncClient { signature, api_url, http })
+  }
+
+  /* Reading, decrypting and maybe migrating the config is blocking work,
+   * so it runs on tokio's blocking thread pool instead of the caller's executor.
+   */
+  pub async fn load(
+    custom_config: Option<&str>,
+    profile: Option<&str>,
+    api_url: Option<&str>,
+    daily_passphrase: &str,
+  ) -> Result<AsyncClient> {
+    let owned = |value: Option<&str>| value.map(|v| v.to_string());
+    let (custom_config, profile, api_url) = (owned(custom_config), owned(profile), owned(api_url));
+    let daily_passphrase = daily_passphrase.to_string();
+
+    tokio::task::spawn_blocking(move || {
+      let stored = Client::load_config(custom_config.as_deref(), profile.as_deref(), &daily_passphrase)?;
+      let api_url = stored.api_url(api_url.as_deref())?;
+      let http = stored.network().reqwest_client(&api_url, &Default::default())?;
+      let signature = Signature::load(stored, &daily_passphrase)?;
+      Ok(AsyncClient { signature, api_url, http })
+    })
+    .await
+    .unwrap_or_else(|err| std::panic::resume_unwind(err.into_panic()))
+  }
+
+  pub async fn register(&self) -> Result<()> {
+    let response = self.http
+      .post(format!("{}/signup", self.api_url))
+      .json(&serde_json::json!({
+        "signed_payload": self.signature.sign_message(b"Hello Constata.eu"),
+      }))
+      .send()
+      .await?;
+    check_status(response).await?;
+    Ok(())
+  }
+
+  pub async fn sign_and_timestamp(&self, bytes: &[u8]) -> Result<DocumentBundle> {
+    self.submit(&self.signature.sign_message(bytes)).await
+  }
+
+  pub async fn submit(&self, signed_payload: &signed_payload::SignedPayload) -> Result<DocumentBundle> {
+    let response = self.http
+      .post(format!("{}/documents/", self.api_url))
+      .json(&serde_json::json!({
+        "signed_payload": signed_payload,
+      }))
+      .send()
+      .await?;
+
+    match check_status(response).await {
+      Ok(response) => Ok(response.json().await?),
+      Err(Error::Api { status: 422, body }) => Err(Error::already_stamped(&body)),
+      Err(err) => Err(err),
+    }
+  }
+
+  pub async fn sign_and_timestamp_path(&self, path: &str) -> Result<DocumentBundle> {
+    self.sign_and_timestamp(&read_document_file(path)?).await
+  }
+
+  pub async fn verify_website(&self, website: &[u8]) -> Result<(String, String)> {
+    let signed_payload = self.signature.sign_message(website);
+    let response = self.http
+      .post(format!("{}/pubkey_domain_endorsements/", self.api_url))
+      .json(&serde_json::json!({
+        "signed_payload": &signed_payload,
+      }))
+      .send()
+      .await?;
+    let response: serde_json::Value = check_status(response).await?.json().await?;
+    Ok((serde_json::to_string_pretty(&response)?, signed_payload.signature.to_string()))
+  }
+
+  pub async fn website_verifications(&self) -> Result<Vec<PubkeyDomainEndorsement>> {
+    Ok(self.get_response("/pubkey_domain_endorsements").await?.json().await?)
+  }
+
+  pub async fn get_response(&self, url: &str) -> Result<reqwest::Response> {
+    let response = self.http
+      .get(format!("{}{}", self.api_url, url))
+      .header("Authentication", self.signature.auth_token(url)?)
+      .send()
+      .await?;
+    check_status(response).await
+  }
+
+  pub async fn get(&self, url: &str) -> Result<String> {
+    Ok(self.get_response(url).await?.text().await?)
+  }
+
+  pub async fn documents(&self) -> Result<Vec<DocumentBundle>> {
+    Ok(self.get_response("/documents").await?.json().await?)
+  }
+
+  pub async fn document(&self, document_id: &str) -> Result<DocumentBundle> {
+    Ok(self.get_response(&format!("/documents/{}", document_id)).await?.json().await?)
+  }
+
+  pub async fn fetch_proof(&self, document_id: &str) -> Result<String> {
+    self.get(&format!("/documents/{}/html_proof", document_id)).await
+  }
+
+  pub async fn fetch_each_proof(&self, document_id: &str) -> Result<Vec<u8>> {
+    let response = self.get_response(&format!("/documents/{}/each_part_html_proof", document_id)).await?;
+    let bytes = response.bytes().await?.to_vec();
+    proofs::check_zip(&bytes)?;
+    Ok(bytes)
+  }
+
+  pub async fn account_state(&self) -> Result<AccountState> {
+    Ok(self.get_response("/account_state").await?.json().await?)
+  }
+}
+
+async fn check_status(response: reqwest::Response) -> Result<reqwest::Response> {
+  let status = response.status();
+  if status.is_success() {
+    Ok(response)
+  } else {
+    Err(Error::Api { status: status.as_u16(), body: response.text().await.unwrap_or_default() })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use mockito;
+
+  fn test_client() -> AsyncClient {
+    let (config, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    let signature = Signature::load(config, "not_so_secret").unwrap();
+    AsyncClient { signature, api_url: mockito::server_url(), http: reqwest::Client::new() }
+  }
+
+  #[tokio::test]
+  async fn loads_its_config_off_the_executor() {
+    let path = std::env::temp_dir().join(format!("constata_async_conf_{}.json", std::process::id()));
+    let path = path.to_str().unwrap();
+    let (config, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    config.save(path, None).unwrap();
+
+    let client = AsyncClient::load(Some(path), None, Some("http://localhost:1"), "not_so_secret").await.unwrap();
+    assert_eq!(client.api_url, "http://localhost:1");
+    assert!(matches!(
+      AsyncClient::load(Some(path), None, None, "wrong").await,
+      Err(Error::WrongPassword)
+    ));
+
+    std::fs::remove_file(path).unwrap();
+  }
+
+  #[tokio::test]
+  async fn account_state_response() {
+    let client = test_client();
+    let mock = mockito::mock("GET", "/account_state")
+        .match_header("Authentication", mockito::Matcher::Any)
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"missing": "1", "parked_count": 1, "person_id": 19, "token_balance": "0", "total_document_count": 367}"#)
+        .expect(1)
+        .create();
+
+    let account_state = client.account_state().await.unwrap();
+    assert_eq!(account_state.person_id, 19);
+    assert_eq!(account_state.total_document_count, 367);
+
+    mock.assert();
+  }
+
+  #[tokio::test]
+  async fn already_stamped_is_an_error() {
+    let client = test_client();
+    let mock = mockito::mock("POST", "/documents/")
+        .with_status(422)
+        .with_body(r#"{"document_id": "1-9"}"#)
+        .expect(1)
+        .create();
+
+    assert!(matches!(
+      client.sign_and_timestamp(b"async hello").await,
+      Err(Error::AlreadyStamped { document_id: Some(_) })
+    ));
+
+    mock.assert();
+  }
+}
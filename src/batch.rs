@@ -0,0 +1,206 @@
+use super::*;
+use std::path::Path;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchOutcome {
+  Stamped,
+  AlreadyStamped,
+  Failed,
+}
+
+/* One line of the results manifest written by stamp-batch. */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchEntry {
+  pub path: String,
+  pub sha256: Option<String>,
+  pub outcome: BatchOutcome,
+  pub document_id: Option<String>,
+  pub state: Option<DocumentState>,
+  pub error: Option<String>,
+}
+
+impl BatchEntry {
+  fn failed(path: &str, sha256: Option<String>, error: Error) -> BatchEntry {
+    BatchEntry {
+      path: path.to_string(),
+      sha256,
+      outcome: BatchOutcome::Failed,
+      document_id: None,
+      state: None,
+      error: Some(error.to_string()),
+    }
+  }
+}
+
+/* Expands files, directories (recursively) and glob patterns into a sorted,
+ * deduplicated list of file paths.
+ */
+pub fn collect_files(targets: &[&str]) -> Result<Vec<String>> {
+  let mut files = vec![];
+
+  for target in targets {
+    let path = Path::new(target);
+    if path.exists() {
+      collect_path(path, &mut files)?;
+      continue;
+    }
+
+    let mut matched = false;
+    for entry in glob::glob(target)? {
+      collect_path(&entry.map_err(|e| std::io::Error::new(e.error().kind(), e.to_string()))?, &mut files)?;
+      matched = true;
+    }
+    if !matched {
+      return Err(Error::FileNotFound(target.to_string()));
+    }
+  }
+
+  let mut seen = std::collections::HashSet::new();
+  files.retain(|f| seen.insert(f.clone()));
+  Ok(files)
+}
+
+fn collect_path(path: &Path, files: &mut Vec<String>) -> Result<()> {
+  if !path.is_dir() {
+    files.push(path.to_string_lossy().to_string());
+    return Ok(());
+  }
+
+  let mut entries = std::fs::read_dir(path)?
+    .map(|e| e.map(|e| e.path()))
+    .collect::<std::io::Result<Vec<_>>>()?;
+  entries.sort();
+  for entry in entries {
+    collect_path(&entry, files)?;
+  }
+  Ok(())
+}
+
+pub fn write_manifest(path: &str, entries: &[BatchEntry]) -> Result<()> {
+  std::fs::write(path, serde_json::to_string_pretty(entries)?)?;
+  Ok(())
+}
+
+impl Client {
+  /* Stamps every path using up to `parallelism` concurrent uploads.
+   * Files that were already stamped are reported, not treated as failures.
+   * Results are returned in the same order as the given paths.
+   * Files are streamed from disk like in sign_and_timestamp_path_streaming, so big files aren't read into memory.
+   */
+  pub fn sign_and_timestamp_many(&self, paths: &[String], parallelism: usize) -> Vec<BatchEntry> {
+    let queue = Mutex::new(paths.iter().enumerate());
+    let results: Mutex<Vec<Option<BatchEntry>>> = Mutex::new(vec![None; paths.len()]);
+
+    std::thread::scope(|scope| {
+      for _ in 0..parallelism.max(1) {
+        scope.spawn(|| loop {
+          let next = queue.lock().unwrap().next();
+          let (index, path) = match next {
+            Some(n) => n,
+            None => break,
+          };
+          let entry = self.stamp_batch_entry(path);
+          results.lock().unwrap()[index] = Some(entry);
+        });
+      }
+    });
+
+    results.into_inner().unwrap().into_iter().flatten().collect()
+  }
+
+  fn stamp_batch_entry(&self, path: &str) -> BatchEntry {
+    let streamed = match self.sign_path_streaming(path, |_| ()) {
+      Ok(streamed) => streamed,
+      Err(err) => return BatchEntry::failed(path, None, err),
+    };
+    let sha256 = streamed.sha256.clone();
+
+    match self.submit_streaming(&streamed, |_| ()) {
+      Ok(document) => {
+        self.record_in_ledger(path, &sha256, &document);
+        BatchEntry {
+          path: path.to_string(),
+          sha256: Some(sha256),
+          outcome: BatchOutcome::Stamped,
+          document_id: Some(document.id),
+          state: Some(document.state),
+          error: None,
+        }
+      },
+      Err(Error::AlreadyStamped { document_id }) => BatchEntry {
+        path: path.to_string(),
+        sha256: Some(sha256),
+        outcome: BatchOutcome::AlreadyStamped,
+        document_id,
+        state: None,
+        error: None,
+      },
+      Err(err) => BatchEntry::failed(path, Some(sha256), err),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::signed_payload::hexdigest;
+  use mockito;
+
+  fn fixture_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("constata_batch_{}_{}", name, std::process::id()));
+    std::fs::create_dir_all(dir.join("nested")).unwrap();
+    std::fs::write(dir.join("a.txt"), b"first").unwrap();
+    std::fs::write(dir.join("b.pdf"), b"second").unwrap();
+    std::fs::write(dir.join("nested/c.txt"), b"third").unwrap();
+    dir
+  }
+
+  #[test]
+  fn collects_directories_and_globs() {
+    let dir = fixture_dir("collect");
+    let dir_str = dir.to_string_lossy().to_string();
+    let pattern = format!("{}/*.txt", dir_str);
+
+    let files = collect_files(&[dir_str.as_str(), pattern.as_str()]).unwrap();
+    assert_eq!(files, vec![
+      format!("{}/a.txt", dir_str),
+      format!("{}/b.pdf", dir_str),
+      format!("{}/nested/c.txt", dir_str),
+    ]);
+
+    assert!(matches!(collect_files(&["nothing/here/*.txt"]), Err(Error::FileNotFound(_))));
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn stamps_many_files_and_reports_each() {
+    let (config, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    let signature = Signature::load(config, "not_so_secret").unwrap();
+    let client = Client { signature, api_url: mockito::server_url(), ledger: None, transport: transport::default_transport() };
+    let mock = mockito::mock("POST", "/documents/")
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"state":"Parked","id":"1-1","person_id":1,"bulletin_id":null,"parts":[],"created_at":"2022-01-05T08:04:47.166681Z","cost":"1","gift_id":null,"bulletins":{},"buy_tokens_link":null}"#)
+        .expect(3)
+        .create();
+
+    let dir = fixture_dir("stamp");
+    let mut files = collect_files(&[dir.to_str().unwrap()]).unwrap();
+    files.push("missing.txt".to_string());
+
+    let entries = client.sign_and_timestamp_many(&files, 2);
+    assert_eq!(entries.len(), 4);
+    assert_eq!(entries[0].path, files[0]);
+    assert_eq!(entries[0].outcome, BatchOutcome::Stamped);
+    assert_eq!(entries[0].sha256.as_deref(), Some(hexdigest(b"first").as_str()));
+    assert_eq!(entries[0].document_id.as_deref(), Some("1-1"));
+    assert_eq!(entries[0].state, Some(DocumentState::Parked));
+    assert_eq!(entries[3].outcome, BatchOutcome::Failed);
+    assert!(entries[3].sha256.is_none());
+
+    mock.assert();
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
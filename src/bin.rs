@@ -1,37 +1,73 @@
-use clap::{crate_authors, crate_name, crate_version, App, SubCommand};
-use constata_client_lib::Client;
-use dialoguer::{theme::ColorfulTheme, Password, Select};
+mod display;
+mod exit_code;
+mod output;
+
+use clap::{crate_authors, crate_name, crate_version, App, Arg, SubCommand};
+use bitcoin::PublicKey;
+use constata_client_lib::{
+  batch, ledger::{Ledger, LedgerEntry}, proofs, network::NetworkSettings, password::{self, PasswordSource}, signed_payload::SignedPayload,
+  transport::{self, Retrying, TransportOptions, UreqTransport}, verify, wait::Backoff, read_document_file, Client, Config, Error,
+};
+use output::{Format, Output, ProfileSummary, Proof, ProofCheck, Submitted, VerifyResult, Waited};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use dialoguer::{
+  console::{user_attended, Emoji},
+  theme::ColorfulTheme, Input, Password, Select,
+};
 
 fn main() {
   let mut app = App::new(crate_name!())
     .version(crate_version!())
     .author(crate_authors!())
     .about("CLI for Constata.eu's Bitcoin timestamping")
-    .arg_from_usage("-c, --config=[FILE]  'Sets a custom config file'")
-    .arg_from_usage("--password=[PASSWORD] 'Use this daily password. Will prompt for a password if missing.'")
-    .subcommand(
-      SubCommand::with_name("api").about("direct call and response from constata API")
-      .subcommand(
-        SubCommand::with_name("stamp")
-          .about("Timestamps a document. Stores a copy in constata's servers.")
-          .arg_from_usage("<FILE> 'Path to the file to upload and timestamp'")
-      )
-      .subcommand(
-        SubCommand::with_name("list").about("List all your documents")
-      )
-      .subcommand(
-        SubCommand::with_name("show")
-        .about("Show a document's timestamping status.")
-        .arg_from_usage("<ID> 'The document unique ID'")
-      )
-      .subcommand(
-        SubCommand::with_name("website-verifications").about("Shows the status of your website verification")
-      )
+    .after_help(exit_code::HELP)
+    .arg_from_usage("-c, --config=[FILE]  'Sets a custom config file. Otherwise uses CONSTATA_CONFIG, ./constata_conf.json or $XDG_CONFIG_HOME/constata/config.json'")
+    .arg_from_usage("--profile=[NAME] 'Use this profile from the config file instead of the default one'")
+    .arg(Arg::from_usage("--api-url=[URL] 'Talk to this API instead of the one for your environment'").env("CONSTATA_API_URL"))
+    .arg_from_usage("--password=[PASSWORD] 'Use this daily password. Prefer the other sources, arguments show up in your shell history.'")
+    .arg_from_usage("--request-timeout=[SECONDS] 'Give up on API requests that take longer than this (defaults to 120)'")
+    .arg_from_usage("--retries=[N] 'Retry failed API requests this many times (defaults to 3). Uploads are only retried if they never reached the API'")
+    .arg_from_usage("--proxy=[URL] 'Send API requests through this proxy. Otherwise uses HTTPS_PROXY, unless NO_PROXY says not to.'")
+    .arg_from_usage("--ca-cert=[PEM] 'Also trust the certificate authorities in this PEM file'")
+    .arg_from_usage("--client-cert=[PEM] 'Authenticate with this client certificate, along with --client-key'")
+    .arg_from_usage("--client-key=[PEM] 'Private key for --client-cert'")
+    .arg_from_usage("--password-file=[FILE] 'Read the daily password from the first line of this file'")
+    .arg_from_usage("--password-stdin 'Read the daily password from stdin'")
+    .arg(
+      Arg::from_usage("--format=[FORMAT] 'How to print results. Structured formats follow schema/output-v1.json. Named --format because --output is where some commands save files'")
+        .possible_values(output::FORMATS)
+        .default_value("human")
+        .env("CONSTATA_FORMAT")
+        .global(true)
     )
     .subcommand(
       SubCommand::with_name("stamp")
-        .about("Timestamps a document. Stores a copy in constata's servers.")
+        .about("Timestamps a document. Stores a copy in constata's servers, unless --hash-only is used.")
         .arg_from_usage("<FILE> 'Path to the file to upload and timestamp'")
+        .arg_from_usage("--hash-only 'Only send a signed statement with the file sha256, name and size. The file never leaves your machine.'")
+        .arg(Arg::from_usage("--meta=[KEY=VALUE]... 'Extra metadata to include in a --hash-only statement'").number_of_values(1))
+     )
+    .subcommand(
+      SubCommand::with_name("sign")
+        .about("Signs a document without sending it anywhere. Works offline, submit the result later with `submit`.")
+        .arg_from_usage("<FILE> 'Path to the file to sign'")
+        .arg_from_usage("-o, --output=[FILE] 'Where to save the signed payload (defaults to <FILE>.signed.json)'")
+        .arg_from_usage("--hash-only 'Sign a statement with the file sha256, name and size instead of the file itself'")
+        .arg(Arg::from_usage("--meta=[KEY=VALUE]... 'Extra metadata to include in a --hash-only statement'").number_of_values(1))
+     )
+    .subcommand(
+      SubCommand::with_name("submit")
+        .about("Timestamps documents signed with `sign`. Needs no password, only the signed payloads.")
+        .arg_from_usage("<SIGNED>... 'Signed payload files, as written by `sign`'")
+     )
+    .subcommand(
+      SubCommand::with_name("stamp-batch")
+        .about("Timestamps many documents, skipping the ones already stamped. Stores a copy of each in constata's servers.")
+        .arg_from_usage("<TARGETS>... 'Files, directories or glob patterns to stamp. Directories are walked recursively.'")
+        .arg_from_usage("-j, --parallel=[N] 'How many uploads to run at the same time (defaults to 4)'")
+        .arg_from_usage("-m, --manifest=[FILE] 'Where to write the results manifest (defaults to stamp_batch_manifest.json)'")
      )
     .subcommand(
       SubCommand::with_name("list").about("List all your documents")
@@ -45,11 +81,26 @@ fn main() {
       SubCommand::with_name("fetch-proof")
         .about("Downloads a document's self validating HTML proof. A single HTML for all the document parts.")
         .arg_from_usage("<ID> 'The document unique id'")
+        .arg(Arg::from_usage("-o, --output=[FILE] 'Where to save the proof (defaults to <ID>.html). Use - for stdout.'").conflicts_with("output-dir"))
+        .arg_from_usage("--output-dir=[DIR] 'Save the proof as <ID>.html in this directory'")
      )
     .subcommand(
       SubCommand::with_name("fetch-each-proof")
-        .about("Downloads a ZIP file containing one self validating HTML proof for each document part.")
+        .about("Downloads a ZIP file containing one self validating HTML proof for each document part. Run it again to resume a download that was cut short.")
+        .arg_from_usage("<ID> 'The document unique id'")
+        .arg(
+          Arg::from_usage("-o, --output=[PATH] 'Where to save the ZIP (defaults to <ID>.zip), or the directory to extract it into (defaults to <ID>). Use - for stdout.'")
+            .conflicts_with("output-dir")
+        )
+        .arg_from_usage("--output-dir=[DIR] 'Save the ZIP, or the extracted directory, in this directory'")
+        .arg_from_usage("-x, --extract 'Unpack the ZIP into one HTML file per document part. Can not be used with --output -'")
+     )
+    .subcommand(
+      SubCommand::with_name("wait")
+        .about("Waits until a document is confirmed on Bitcoin, then downloads its HTML proof.")
         .arg_from_usage("<ID> 'The document unique id'")
+        .arg_from_usage("-t, --timeout=[SECONDS] 'Give up after this many seconds (defaults to 86400)'")
+        .arg_from_usage("-o, --output=[FILE] 'Where to save the HTML proof (defaults to <ID>.html)'")
      )
     .subcommand(
       SubCommand::with_name("verify-website")
@@ -62,6 +113,87 @@ fn main() {
     .subcommand(
       SubCommand::with_name("website-verifications").about("Shows the status of your website verification")
     )
+    .subcommand(
+      SubCommand::with_name("verify")
+        .about("Verifies a file against a signed payload or an HTML proof. Works offline, no password needed.")
+        .arg_from_usage("<FILE> 'Path to the file to verify'")
+        .arg_from_usage("-s, --signature=[JSON] 'Signed payload, either a path to a JSON file or the JSON itself'")
+        .arg_from_usage("-p, --proof=[HTML] 'Path to a downloaded HTML proof'")
+        .arg_from_usage("-k, --public-key=[KEY] 'Check the file was signed by this public key. Without it or --own-key the signer is not checked.'")
+        .arg(Arg::from_usage("--own-key 'Check the file was signed by the key in your config file'").conflicts_with("public-key"))
+    )
+    .subcommand(
+      SubCommand::with_name("history").about("Lists the documents stamped from this machine, as recorded in the local ledger")
+    )
+    .subcommand(
+      SubCommand::with_name("which")
+        .about("Tells whether a file was stamped from this machine, and under which document id")
+        .arg_from_usage("<FILE> 'Path to the file to look up'")
+    )
+    .subcommand(
+      SubCommand::with_name("sync").about("Refreshes the state of every document in the local ledger")
+    )
+    .subcommand(
+      SubCommand::with_name("init")
+        .about("Creates your key and config file. Use --config and --profile to choose where it's stored.")
+        .arg_from_usage("--non-interactive 'Never prompt. Passwords are read from the environment or from files.'")
+        .arg_from_usage("-e, --environment=[ENV] 'The environment the config is for: production, staging or development (defaults to production)'")
+        .arg_from_usage("--daily-password-file=[FILE] 'Read the daily password from this file instead of CONSTATA_PASSWORD'")
+        .arg_from_usage("--backup-password-file=[FILE] 'Read the master seed password from this file instead of CONSTATA_BACKUP_PASSWORD'")
+        .arg_from_usage("--mnemonic-output=[FILE] 'Write the master seed as JSON to this file, readable only by you. Prints it to stdout otherwise.'")
+    )
+    .subcommand(
+      SubCommand::with_name("recover")
+        .about("Rebuilds your config file from your master seed words and password.")
+        .arg_from_usage("-e, --environment=[ENV] 'The environment the config is for (defaults to production)'")
+        .arg_from_usage("--key-index=[N] 'Which key to recover, if you rotated your key before (defaults to 0)'")
+    )
+    .subcommand(
+      SubCommand::with_name("rotate-key")
+        .about("Replaces your signing key with a new one derived from your master seed. Use it if your daily key was compromised, then email the rotation statement to hola@constata.eu to revoke the old key.")
+        .arg_from_usage("-o, --statement=[FILE] 'Where to save the signed key rotation statement (defaults to key_rotation.json)'")
+    )
+    .subcommand(
+      SubCommand::with_name("change-password")
+        .about("Encrypts your key with a new daily password. Your key and master seed stay the same.")
+    )
+    .subcommand(
+      SubCommand::with_name("network")
+        .about("Shows the proxy and TLS settings stored in your profile")
+        .subcommand(SubCommand::with_name("save").about("Stores the --proxy, --ca-cert, --client-cert and --client-key given in your profile"))
+        .subcommand(SubCommand::with_name("clear").about("Removes every proxy and TLS setting from your profile"))
+    )
+    .subcommand(
+      SubCommand::with_name("password")
+        .about("Remembers your daily password in your OS keyring so you're not asked for it again. Needs a build with the \"keyring\" feature. Where there's no keyring, set CONSTATA_SECRETS_FILE to keep it unencrypted in that file instead.")
+        .subcommand(SubCommand::with_name("save").about("Checks your daily password and remembers it"))
+        .subcommand(SubCommand::with_name("forget").about("Forgets your daily password"))
+    )
+    .subcommand(
+      SubCommand::with_name("profile")
+        .about("Manages the profiles in your config file. Each profile has its own key.")
+        .subcommand(SubCommand::with_name("list").about("Lists your profiles"))
+        .subcommand(
+          SubCommand::with_name("add")
+            .about("Creates a new key and stores it as a new profile")
+            .arg_from_usage("<NAME> 'The profile name'")
+            .arg_from_usage("-e, --environment=[ENV] 'The environment the profile is for: production, staging or development (defaults to production)'")
+        )
+        .subcommand(
+          SubCommand::with_name("remove")
+            .about("Removes a profile and its key. Make sure you have its master seed before doing this.")
+            .arg_from_usage("<NAME> 'The profile name'")
+        )
+        .subcommand(
+          SubCommand::with_name("default")
+            .about("Sets the profile to use when --profile is not given")
+            .arg_from_usage("<NAME> 'The profile name'")
+        )
+    )
+    .subcommand(
+      SubCommand::with_name("signup")
+        .about("Registers your public key with constata. Other commands do it too, the first time they're used with a new key.")
+    )
     .subcommand(
       SubCommand::with_name("account-state")
       .about("Show person's account state including token balance and documents pending to be stamped")
@@ -70,124 +202,669 @@ fn main() {
   let mut help = vec![];
   app.write_long_help(&mut help).unwrap();
 
-  let matches = app.get_matches();
+  let matches = match app.get_matches_safe() {
+    Ok(matches) => matches,
+    Err(err) if err.use_stderr() => {
+      eprintln!("{}", err.message);
+      std::process::exit(exit_code::USAGE);
+    },
+    Err(err) => err.exit(),
+  };
 
   let config_path = matches.value_of("config");
+  let profile = matches.value_of("profile");
 
-  if Client::config_needed(config_path) {
-    println!(
+  let ledger = Ledger::for_config(&Client::config_path(config_path), profile);
+  let format: Format = matches.value_of("format").unwrap_or("human").parse().expect("--format to be a known format");
+  let out = Output::new(format, &command_name(&matches));
+
+  match matches.subcommand() {
+    ("verify", Some(sub)) => return verify_flow(&out, sub, config_path, profile),
+    ("recover", Some(sub)) => return recover_flow(&out, sub, config_path, profile),
+    ("rotate-key", Some(sub)) => return rotate_key_flow(&out, sub, config_path, profile),
+    ("change-password", Some(_)) => return change_password_flow(&out, &matches, config_path, profile),
+    ("profile", Some(sub)) => return profile_flow(&out, sub, config_path),
+    ("network", Some(sub)) => return network_flow(&out, &matches, sub, config_path, profile),
+    ("password", Some(sub)) => return password_flow(&out, &matches, sub, config_path, profile),
+    ("init", Some(sub)) => return init_flow(&out, sub, config_path, profile),
+    ("sign", Some(sub)) => return sign_flow(&out, &matches, sub, config_path, profile),
+    ("submit", Some(sub)) => return submit_flow(&out, &matches, sub, config_path, profile, &ledger),
+    ("history", Some(_)) => return out.print(&or_exit(ledger.entries()), |entries| display::history(entries)),
+    ("which", Some(sub)) => {
+      let path = sub.value_of("FILE").expect("FILE to be set");
+      return match or_exit(ledger.find_file(path)) {
+        Some(entry) => out.print(&entry, display::ledger_entry),
+        None => exit_with(exit_code::NOT_FOUND, &format!("{} was not stamped from this machine", path)),
+      };
+    },
+    _ => {},
+  }
+
+  if Client::config_needed(config_path, profile) {
+    if !user_attended() {
+      exit_with(exit_code::AUTH, &format!(
+        "No config found in {}. Create one with `constata-cli init --non-interactive`.",
+        Client::config_path(config_path),
+      ));
+    }
+
+    out.say(&format!(
       "\
       Constata's API authenticates you using your own private key.\n\
       This key is never sent to our servers, and is stored encrypted in your drive.\n\
-      We looked here for a config file named {} and couldn't find any.\n\
+      We looked for a config file in {} and couldn't find any{}.\n\
       If you already have a config file bring it over, otherwise, we can create one now.
     ",
-      Client::config_path(config_path)
-    );
+      Client::config_path(config_path),
+      profile.map(|p| format!(" with a profile named {}", p)).unwrap_or_default(),
+    ));
 
     let items = vec![
       "Let's create one now.",
       "Exit for now. I'll bring my config over.",
     ];
 
-    let selection = Select::with_theme(&ColorfulTheme::default())
+    let selection = or_exit(Select::with_theme(&ColorfulTheme::default())
       .with_prompt("What do you want to do?")
       .items(&items)
       .default(0)
       .interact()
-      .expect("Need to select an action");
+      .map_err(Error::from));
 
     if selection == 1 {
-      return println!("Ok, copy your config file here and try again.");
+      return out.say("Ok, copy your config file here and try again.");
     } else {
-      create_config_file();
+      create_config_file(&out, config_path, profile, None);
     }
   }
 
-  let daily_pass = matches
-    .value_of("password")
-    .map(|i| i.to_string())
-    .unwrap_or_else(|| {
-      Password::with_theme(&ColorfulTheme::default())
-        .with_prompt("Enter your password")
-        .interact()
-        .unwrap()
-    });
-
-  let client = Client::load(config_path, &daily_pass).unwrap();
-
-  let result = match matches.subcommand() {
-    ("api", Some(sub)) => {
-      if let Some(stamp) = sub.subcommand_matches("stamp") {
-        client
-          .sign_and_timestamp_path(&stamp.value_of("FILE").expect("FILE to be set"), true)
-          .expect("Sign and timestamp to succeed")
-          .as_bytes()
-          .to_vec()
-      } else if sub.is_present("list") {
-        client.documents().unwrap().as_bytes().to_vec()
-      } else if let Some(show) = sub.subcommand_matches("show") {
-        client
-          .document(&show.value_of("ID").unwrap(), true)
-          .unwrap()
-          .as_bytes()
-          .to_vec()
-      } else if sub.is_present("website-verifications") {
-        client.website_verifications(true).unwrap().as_bytes().to_vec()
+  let daily_pass = daily_password(&matches, config_path, profile, "Enter your password");
+
+  let client = or_exit(Client::load_with(
+    config_path,
+    profile,
+    matches.value_of("api-url"),
+    &daily_pass,
+    transport_options(&matches),
+    &network_overrides(&matches),
+  ));
+  let stored = or_exit(Config::load(&Client::config_path(config_path), profile));
+
+  let signup = matches.subcommand_name() == Some("signup");
+  if signup || !stored.is_registered() {
+    or_exit(client.register());
+    or_exit(Client::mark_registered(config_path, profile));
+  }
+
+  match matches.subcommand() {
+    ("stamp", Some(sub)) => {
+      let path = sub.value_of("FILE").expect("FILE to be set");
+      let document = if sub.is_present("hash-only") {
+        or_exit(client.stamp_digest_path(path, digest_metadata(sub)))
+      } else {
+        let bar = display::progress_bar(0);
+        let result = client.sign_and_timestamp_path_streaming(path, |phase, done, size| {
+          bar.set_length(size);
+          display::update_progress(&bar, phase, done);
+        });
+        bar.finish_and_clear();
+        or_exit(result)
+      };
+      warn_unrecorded(&client);
+      out.print(&document, display::stamped_document);
+    },
+    ("stamp-batch", Some(sub)) => {
+      let targets: Vec<&str> = sub.values_of("TARGETS").expect("TARGETS to be set").collect();
+      let parallel = number_arg(sub, "parallel", 4);
+      let manifest = sub.value_of("manifest").unwrap_or("stamp_batch_manifest.json");
+      let files = or_exit(batch::collect_files(&targets));
+      let entries = client.sign_and_timestamp_many(&files, parallel);
+      warn_unrecorded(&client);
+      or_exit(batch::write_manifest(manifest, &entries));
+      out.print(&entries, |entries| display::batch_results(entries, manifest));
+      if entries.iter().any(|entry| entry.outcome == batch::BatchOutcome::Failed) {
+        std::process::exit(exit_code::FAILURE);
+      }
+    },
+    ("sync", Some(_)) => {
+      let sync = or_exit(client.sync_ledger());
+      out.print(&sync, display::synced);
+      if !sync.failed.is_empty() {
+        std::process::exit(exit_code::FAILURE);
+      }
+    },
+    ("list", Some(_)) => out.print(&or_exit(client.documents()), |documents| display::document_list(documents)),
+    ("show", Some(sub)) => out.print(&or_exit(client.document(sub.value_of("ID").expect("ID to be set"))), display::document),
+    ("fetch-proof", Some(sub)) => {
+      let id = sub.value_of("ID").expect("ID to be set");
+      let html = or_exit(client.fetch_proof(id));
+      let proof = Proof::new(id, "text/html", html.as_bytes());
+      if sub.value_of("output") == Some("-") {
+        return out.print(&proof.with_content(html.as_bytes()), |_| html.clone());
+      }
+      let path = proof_path(sub, proofs::proof_file_name(id));
+      or_exit(proofs::save(&path, html.as_bytes()));
+      out.print(&proof.saved_to(&path, &[]), display::saved_proof);
+    },
+    ("fetch-each-proof", Some(sub)) => {
+      let id = sub.value_of("ID").expect("ID to be set");
+      if sub.is_present("extract") && sub.value_of("output") == Some("-") {
+        exit_with(exit_code::USAGE, "--extract writes one file per document part, it can't write to stdout. Pass a directory to --output.");
+      }
+      let saved_path = (!sub.is_present("extract") && sub.value_of("output") != Some("-"))
+        .then(|| proof_path(sub, proofs::each_proof_file_name(id)));
+      let bar = display::progress_bar(0);
+      let progress = |done, total| display::download_progress(&bar, done, total);
+      let result = match &saved_path {
+        Some(path) => client.download_each_proof_with(id, path, progress),
+        None => client.fetch_each_proof_with(id, progress),
+      };
+      bar.finish_and_clear();
+      let zip = or_exit(result);
+      let proof = Proof::new(id, "application/zip", &zip);
+
+      if let Some(path) = saved_path {
+        out.print(&proof.saved_to(&path, &[]), display::saved_proof);
+      } else if sub.is_present("extract") {
+        let dir = proof_path(sub, id.to_string());
+        let files = or_exit(proofs::extract_zip(&zip, &dir));
+        out.print(&proof.saved_to(&dir, &files), display::saved_proof);
+      } else if out.is_human() {
+        use std::io::Write;
+        or_exit(std::io::stdout().write_all(&zip).map_err(Error::from));
       } else {
-        help
+        out.print(&proof.with_content(&zip), |_| String::new());
+      }
+    },
+    ("wait", Some(sub)) => {
+      let id = sub.value_of("ID").expect("ID to be set");
+      let timeout = number_arg(sub, "timeout", 86400);
+      let output = sub.value_of("output").map(|o| o.to_string()).unwrap_or_else(|| proofs::proof_file_name(id));
+      let document = or_exit(client.wait_for_bulletin_with(id, Duration::from_secs(timeout), &Backoff::default(), |document| {
+        out.event(document, display::document_transition);
+      }));
+      let proof = or_exit(client.fetch_proof(id));
+      or_exit(proofs::save(&output, proof.as_bytes()));
+      out.print(&Waited { document, proof_path: output }, |waited| display::field("Proof saved to:", &waited.proof_path));
+    },
+    ("verify-website", Some(sub)) => verify_website_flow(&out, &client, sub.value_of("URL").expect("URL TO BE SET")),
+    ("website-verifications", Some(_)) =>
+      out.print(&or_exit(client.website_verifications()), |sites| display::website_verifications(sites)),
+    ("signup", Some(_)) => out.print(
+      &serde_json::json!({ "public_key": stored.public_key().to_string(), "registered": true }),
+      |_| "Your public key is registered with constata".to_string(),
+    ),
+    ("account-state", Some(_)) => out.print(&or_exit(client.account_state()), display::account_state),
+    _ => println!("{}", String::from_utf8_lossy(&help)),
+  }
+}
+
+/* Where to save a downloaded proof: --output, or `default_name` inside --output-dir or the current directory. */
+fn proof_path(matches: &clap::ArgMatches, default_name: String) -> PathBuf {
+  match (matches.value_of("output"), matches.value_of("output-dir")) {
+    (Some(output), _) => PathBuf::from(output),
+    (None, Some(dir)) => {
+      or_exit(std::fs::create_dir_all(dir).map_err(Error::from));
+      Path::new(dir).join(default_name)
+    },
+    (None, None) => PathBuf::from(default_name),
+  }
+}
+
+/* The subcommand path, like "profile list", used as the command in structured output. */
+fn command_name(matches: &clap::ArgMatches) -> String {
+  let mut names = vec![];
+  let mut current = matches;
+  while let (name, Some(sub)) = current.subcommand() {
+    names.push(name);
+    current = sub;
+  }
+  names.join(" ")
+}
+
+fn or_exit<T>(result: constata_client_lib::Result<T>) -> T {
+  result.unwrap_or_else(|err| exit_with(exit_code::for_error(&err), &err.to_string()))
+}
+
+/* Documents that were stamped but could not be added to the ledger. */
+fn warn_unrecorded(client: &Client) {
+  for warning in client.ledger().map(|ledger| ledger.take_warnings()).unwrap_or_default() {
+    eprintln!("{} {}", Emoji("⚠️", "WARNING:"), warning);
+  }
+}
+
+fn exit_with(code: i32, message: &str) -> ! {
+  eprintln!("\n {} {}\n", Emoji("🚨", "*"), message);
+  std::process::exit(code);
+}
+
+fn number_arg<T: std::str::FromStr>(matches: &clap::ArgMatches, name: &str, default: T) -> T {
+  match matches.value_of(name) {
+    Some(value) => value
+      .parse()
+      .unwrap_or_else(|_| exit_with(exit_code::USAGE, &format!("--{} expects a number, got {}", name, value))),
+    None => default,
+  }
+}
+
+fn verify_flow(out: &Output, matches: &clap::ArgMatches, config_path: Option<&str>, profile: Option<&str>) {
+  let path = matches.value_of("FILE").expect("FILE to be set");
+  let bytes = or_exit(read_document_file(path));
+
+  if matches.value_of("signature").is_none() && matches.value_of("proof").is_none() {
+    exit_with(exit_code::USAGE, &format!("Use --signature or --proof to tell what to verify {} against", path));
+  }
+
+  let expected_signer = match matches.value_of("public-key") {
+    Some(key) => Some(key.parse::<PublicKey>().unwrap_or_else(|_| {
+      exit_with(exit_code::USAGE, &format!("{} is not a valid public key", key))
+    })),
+    None if matches.is_present("own-key") => {
+      if Client::config_needed(config_path, profile) {
+        exit_with(exit_code::AUTH, &format!("--own-key needs a config, none found in {}", Client::config_path(config_path)));
+      }
+      Some(or_exit(Config::load(&Client::config_path(config_path), profile)).public_key())
+    },
+    None => None,
+  };
+
+  let mut result = VerifyResult { file: path.to_string(), ok: true, signature: None, proof: None };
+
+  if let Some(signature) = matches.value_of("signature") {
+    let json = if signature.trim_start().starts_with('{') {
+      signature.to_string()
+    } else {
+      or_exit(std::fs::read_to_string(signature).map_err(Error::from))
+    };
+    let signed_payload: SignedPayload = or_exit(serde_json::from_str(&json).map_err(Error::from));
+    let verification = verify::verify_signed_payload(&bytes, &signed_payload, expected_signer.as_ref());
+    result.ok &= verification.is_ok();
+    result.signature = Some(verification);
+  }
+
+  if let Some(proof) = matches.value_of("proof") {
+    let html = or_exit(std::fs::read_to_string(proof).map_err(Error::from));
+    let referenced = verify::proof_references(&bytes, &html);
+    result.ok &= referenced;
+    result.proof = Some(ProofCheck { path: proof.to_string(), referenced });
+  }
+
+  out.print(&result, display::verify_result);
+
+  if !result.ok {
+    std::process::exit(exit_code::FAILURE);
+  }
+}
+
+fn prompt_seed() -> (String, String) {
+  let words: String = or_exit(Input::with_theme(&ColorfulTheme::default())
+    .with_prompt("Type your 12 master seed words, separated by spaces")
+    .interact_text()
+    .map_err(Error::from));
+
+  let backup_pass = or_exit(Password::with_theme(&ColorfulTheme::default())
+    .with_prompt("Type your master seed password")
+    .interact()
+    .map_err(Error::from));
+
+  (words.split_whitespace().collect::<Vec<_>>().join(" "), backup_pass)
+}
+
+fn prompt_new_daily_password() -> String {
+  or_exit(Password::with_theme(&ColorfulTheme::default())
+    .with_prompt("Type a new daily password")
+    .with_confirmation("Repeat password", "Error: the passwords don't match.")
+    .interact()
+    .map_err(Error::from))
+}
+
+fn recover_flow(out: &Output, matches: &clap::ArgMatches, config_path: Option<&str>, profile: Option<&str>) {
+  let key_index = number_arg(matches, "key-index", 0);
+  let (words, backup_pass) = prompt_seed();
+  let daily_pass = prompt_new_daily_password();
+
+  or_exit(Client::recover(config_path, profile, matches.value_of("environment"), &words, &backup_pass, &daily_pass, key_index));
+  let path = Client::config_path(config_path);
+  let config = or_exit(Config::load(&path, profile));
+
+  out.print(
+    &serde_json::json!({ "config": path, "public_key": config.public_key().to_string() }),
+    |_| format!("Your key was recovered and saved in {}, encrypted with your new daily password.", path),
+  );
+}
+
+fn rotate_key_flow(out: &Output, matches: &clap::ArgMatches, config_path: Option<&str>, profile: Option<&str>) {
+  let statement_path = matches.value_of("statement").unwrap_or("key_rotation.json");
+  let (words, backup_pass) = prompt_seed();
+  let daily_pass = prompt_new_daily_password();
+
+  let statement = or_exit(Client::rotate_key(config_path, profile, &words, &backup_pass, &daily_pass));
+  or_exit(std::fs::write(statement_path, serde_json::to_string_pretty(&statement).unwrap()).map_err(Error::from));
+  let path = Client::config_path(config_path);
+  let config = or_exit(Config::load(&path, profile));
+
+  out.print(
+    &serde_json::json!({ "config": path, "public_key": config.public_key().to_string(), "statement": statement_path }),
+    |_| format!(
+      "\
+      Your config in {} now holds a new key, encrypted with your new daily password.\n\
+      The key rotation statement, signed with your old key, was saved to {}.\n\
+      It was not sent anywhere, and the old key is still valid on constata's servers.\n\
+      Email {} to hola@constata.eu to have the old key revoked.",
+      path,
+      statement_path,
+      statement_path
+    ),
+  );
+}
+
+fn change_password_flow(out: &Output, matches: &clap::ArgMatches, config_path: Option<&str>, profile: Option<&str>) {
+  let old_pass = daily_password(matches, config_path, profile, "Enter your current daily password");
+  let new_pass = prompt_new_daily_password();
+
+  or_exit(Client::change_password(config_path, profile, &old_pass, &new_pass));
+  let path = Client::config_path(config_path);
+  let mut message = format!("Your key in {} is now encrypted with your new daily password.", path);
+
+  let account = Client::password_account(config_path, profile);
+  let remembered = match password::default_secret_store() {
+    Some(store) => or_exit(store.get(&account)).is_some(),
+    None => false,
+  };
+  if remembered {
+    or_exit(Client::remember_password(config_path, profile, &new_pass));
+    message.push_str("\nYour remembered daily password was updated too.");
+  }
+
+  out.print(&serde_json::json!({ "config": path, "remembered_password_updated": remembered }), |_| message);
+}
+
+/* Proxy and TLS flags given for this run, they win over the ones stored in the profile. */
+fn network_overrides(matches: &clap::ArgMatches) -> NetworkSettings {
+  NetworkSettings {
+    proxy: matches.value_of("proxy").map(|v| v.to_string()),
+    ca_cert: matches.value_of("ca-cert").map(|v| v.to_string()),
+    client_cert: matches.value_of("client-cert").map(|v| v.to_string()),
+    client_key: matches.value_of("client-key").map(|v| v.to_string()),
+  }
+}
+
+fn transport_options(matches: &clap::ArgMatches) -> TransportOptions {
+  let mut options = TransportOptions::default();
+  if matches.is_present("request-timeout") {
+    options.read_timeout = Duration::from_secs(number_arg(matches, "request-timeout", 120));
+  }
+  if matches.is_present("retries") {
+    options.max_attempts = 1 + number_arg::<u32>(matches, "retries", 3);
+  }
+  options
+}
+
+fn http_transport(matches: &clap::ArgMatches, stored: &NetworkSettings, api_url: &str) -> Retrying<UreqTransport> {
+  or_exit(transport::http_with(transport_options(matches), &stored.merge(&network_overrides(matches)), api_url))
+}
+
+fn network_flow(out: &Output, global: &clap::ArgMatches, matches: &clap::ArgMatches, config_path: Option<&str>, profile: Option<&str>) {
+  let path = Client::config_path(config_path);
+  let mut config = or_exit(Config::load(&path, profile));
+
+  let message = match matches.subcommand_name() {
+    Some("save") => {
+      let settings = config.network().merge(&network_overrides(global));
+      config.set_network(settings);
+      or_exit(config.save(&path, profile));
+      None
+    },
+    Some("clear") => {
+      config.set_network(NetworkSettings::default());
+      or_exit(config.save(&path, profile));
+      Some("Proxy and TLS settings were removed from your profile".to_string())
+    },
+    _ => None,
+  };
+
+  let shown = NetworkSettings {
+    proxy: config.network().proxy.as_deref().map(display::without_credentials),
+    ..config.network().clone()
+  };
+  out.print(&shown, |settings| message.unwrap_or_else(|| display::network(settings)));
+}
+
+/* Where the daily password is read from, in order of preference. */
+fn daily_password_sources(matches: &clap::ArgMatches, config_path: Option<&str>, profile: Option<&str>) -> Vec<PasswordSource> {
+  let mut sources = vec![];
+  if let Some(password) = matches.value_of("password") {
+    sources.push(PasswordSource::Literal(password.to_string()));
+  }
+  if let Some(file) = matches.value_of("password-file") {
+    sources.push(PasswordSource::File(file.into()));
+  }
+  if matches.is_present("password-stdin") {
+    sources.push(PasswordSource::Stdin);
+  }
+  sources.push(PasswordSource::Env("CONSTATA_PASSWORD".to_string()));
+  sources.push(PasswordSource::SecretStore(Client::password_account(config_path, profile)));
+  sources
+}
+
+fn daily_password(matches: &clap::ArgMatches, config_path: Option<&str>, profile: Option<&str>, prompt: &str) -> String {
+  match or_exit(password::resolve(&daily_password_sources(matches, config_path, profile))) {
+    Some(password) => password,
+    None if !user_attended() => or_exit(Err(Error::PasswordMissing)),
+    None => or_exit(Password::with_theme(&ColorfulTheme::default())
+      .with_prompt(prompt)
+      .interact()
+      .map_err(Error::from)),
+  }
+}
+
+fn password_flow(out: &Output, global: &clap::ArgMatches, matches: &clap::ArgMatches, config_path: Option<&str>, profile: Option<&str>) {
+  match matches.subcommand() {
+    ("forget", Some(_)) => {
+      or_exit(Client::forget_password(config_path, profile));
+      out.print(&serde_json::json!({ "remembered": false }), |_| "Your daily password was forgotten".to_string());
+    },
+    _ => {
+      let mut sources = daily_password_sources(global, config_path, profile);
+      sources.retain(|source| !matches!(source, PasswordSource::SecretStore(_)));
+      let daily_pass = or_exit(password::resolve(&sources)).unwrap_or_else(|| {
+        or_exit(Password::with_theme(&ColorfulTheme::default())
+          .with_prompt("Enter your daily password")
+          .interact()
+          .map_err(Error::from))
+      });
+      or_exit(Client::remember_password(config_path, profile, &daily_pass));
+      let plaintext_path = password::default_secret_store()
+        .and_then(|store| store.plaintext_path(&Client::password_account(config_path, profile)));
+      if let Some(path) = &plaintext_path {
+        eprintln!(
+          "{} Your daily password is stored unencrypted in {}, as CONSTATA_SECRETS_FILE says.\n\
+          Anyone who can read that file can use your key.",
+          Emoji("⚠️", "WARNING:"),
+          path.display()
+        );
       }
+      out.print(
+        &serde_json::json!({ "remembered": true, "plaintext_path": plaintext_path }),
+        |_| "Your daily password was saved, you won't be asked for it again".to_string(),
+      );
     },
-    ("stamp", Some(sub)) => client
-      .sign_and_timestamp_path(&sub.value_of("FILE").expect("FILE to be set"), false)
-      .expect("Sign and timestamp to succeed")
-      .as_bytes()
-      .to_vec(),
-    ("list", Some(_)) => client.list_documents().unwrap().as_bytes().to_vec(),
-    ("show", Some(sub)) => client
-      .document(&sub.value_of("ID").unwrap(), false)
-      .unwrap()
-      .as_bytes()
-      .to_vec(),
-    ("fetch-proof", Some(sub)) => client
-      .fetch_proof(&sub.value_of("ID").unwrap())
-      .unwrap()
-      .as_bytes()
-      .to_vec(),
-    ("fetch-each-proof", Some(sub)) => client
-      .fetch_each_proof(&sub.value_of("ID").unwrap())
-      .unwrap(),
-    ("verify-website", Some(sub)) =>
-      verify_website_flow(&client, &sub.value_of("URL").expect("URL TO BE SET"))
-        .as_bytes()
-        .to_vec(),
-    ("website-verifications", Some(_)) => client.website_verifications(false).unwrap().as_bytes().to_vec(),
-    ("account-state", Some(_)) => client
-      .account_state().unwrap().as_bytes().to_vec(),
-    _ => help,
+  }
+}
+
+fn profile_flow(out: &Output, matches: &clap::ArgMatches, config_path: Option<&str>) {
+  let path = Client::config_path(config_path);
+  let mut profiles = or_exit(Client::profiles(config_path));
+
+  let message = match matches.subcommand() {
+    ("add", Some(sub)) => {
+      let name = sub.value_of("NAME").expect("NAME to be set");
+      if profiles.contains(Some(name)) {
+        exit_with(exit_code::USAGE, &format!("There is already a profile named {} in {}", name, path));
+      }
+      create_config_file(out, config_path, Some(name), sub.value_of("environment"));
+      profiles = or_exit(Client::profiles(config_path));
+      None
+    },
+    ("remove", Some(sub)) => {
+      let name = sub.value_of("NAME").expect("NAME to be set");
+      or_exit(profiles.remove(name));
+      or_exit(profiles.save(&path));
+      Some(format!("Profile {} was removed from {}", name, path))
+    },
+    ("default", Some(sub)) => {
+      let name = sub.value_of("NAME").expect("NAME to be set");
+      or_exit(profiles.set_default(name));
+      or_exit(profiles.save(&path));
+      Some(format!("Profile {} is now your default profile", name))
+    },
+    _ => None,
+  };
+
+  out.print(&ProfileSummary::all(&profiles), |_| message.unwrap_or_else(|| display::profiles(&profiles, &path)));
+}
+
+fn secret_from(env_var: &str, file: Option<&str>) -> Option<String> {
+  match file {
+    Some(path) => Some(or_exit(std::fs::read_to_string(path).map_err(Error::from)).trim_end_matches(&['\r', '\n'][..]).to_string()),
+    None => std::env::var(env_var).ok().filter(|s| !s.is_empty()),
+  }
+}
+
+fn init_flow(out: &Output, matches: &clap::ArgMatches, config_path: Option<&str>, profile: Option<&str>) {
+  let env = matches.value_of("environment");
+
+  if !Client::config_needed(config_path, profile) {
+    exit_with(exit_code::USAGE, &format!("There is already a key in {}", Client::config_path(config_path)));
+  }
+
+  if !matches.is_present("non-interactive") {
+    return create_config_file(out, config_path, profile, env);
+  }
+
+  let missing = |what: &str, var: &str, flag: &str| -> String {
+    exit_with(exit_code::AUTH, &format!("Missing the {}. Set {} or use {}", what, var, flag))
+  };
+
+  let daily_pass = secret_from("CONSTATA_PASSWORD", matches.value_of("daily-password-file"))
+    .unwrap_or_else(|| missing("daily password", "CONSTATA_PASSWORD", "--daily-password-file"));
+  let backup_pass = secret_from("CONSTATA_BACKUP_PASSWORD", matches.value_of("backup-password-file"))
+    .unwrap_or_else(|| missing("master seed password", "CONSTATA_BACKUP_PASSWORD", "--backup-password-file"));
+
+  let words = or_exit(Client::create(config_path, profile, env, &backup_pass, &daily_pass));
+  let path = Client::config_path(config_path);
+  let config = or_exit(Config::load(&path, profile));
+
+  let mut seed = serde_json::json!({
+    "config": path,
+    "profile": profile,
+    "environment": config.environment(),
+    "public_key": config.public_key().to_string(),
+    "mnemonic": words,
+  });
+
+  if let Some(output) = matches.value_of("mnemonic-output") {
+    or_exit(constata_client_lib::write_private_file(output, serde_json::to_string_pretty(&seed).unwrap().as_bytes()));
+    seed.as_object_mut().expect("seed to be an object").remove("mnemonic");
+    seed["mnemonic_output"] = output.into();
+  }
+
+  out.print(&seed, |seed| match matches.value_of("mnemonic-output") {
+    Some(output) => display::field("Master seed saved to:", output),
+    None => serde_json::to_string_pretty(seed).unwrap(),
+  });
+}
+
+fn digest_metadata(matches: &clap::ArgMatches) -> BTreeMap<String, String> {
+  matches
+    .values_of("meta")
+    .into_iter()
+    .flatten()
+    .map(|entry| match entry.split_once('=') {
+      Some((key, value)) => (key.to_string(), value.to_string()),
+      None => exit_with(exit_code::USAGE, &format!("--meta expects KEY=VALUE, got {}", entry)),
+    })
+    .collect()
+}
+
+fn sign_flow(out: &Output, global: &clap::ArgMatches, matches: &clap::ArgMatches, config_path: Option<&str>, profile: Option<&str>) {
+  let path = matches.value_of("FILE").expect("FILE to be set");
+  let output = matches.value_of("output").map(|o| o.to_string()).unwrap_or_else(|| format!("{}.signed.json", path));
+
+  if Client::config_needed(config_path, profile) {
+    exit_with(exit_code::AUTH, &format!("No config found in {}. Create one with `constata-cli init`.", Client::config_path(config_path)));
+  }
+
+  let daily_pass = daily_password(global, config_path, profile, "Enter your password");
+  let client = or_exit(Client::load(config_path, profile, global.value_of("api-url"), &daily_pass));
+  let signed = if matches.is_present("hash-only") {
+    or_exit(client.sign_digest_path(path, digest_metadata(matches)))
+  } else {
+    or_exit(client.sign_path(path))
   };
+  or_exit(std::fs::write(&output, serde_json::to_string_pretty(&signed).unwrap()).map_err(Error::from));
 
-  use std::io::Write;
-  std::io::stdout().write_all(&result).unwrap();
-  println!("");
+  out.print(
+    &serde_json::json!({ "file": path, "signed_payload_path": output, "signer": signed.signer.to_string() }),
+    |_| display::field("Signed payload saved to:", &output),
+  );
 }
 
-fn verify_website_flow(client: &Client, website: &str) -> String {
-  let (_response, signature) = client
-    .verify_website(website.as_bytes())
-    .expect("Verify website to succeed");
+fn submit_flow(out: &Output, global: &clap::ArgMatches, matches: &clap::ArgMatches, config_path: Option<&str>, profile: Option<&str>, ledger: &Ledger) {
+  let (api_url, network) = if Client::config_needed(config_path, profile) {
+    let url = global.value_of("api-url").map_or_else(|| constata_client_lib::environment_url("production"), Ok);
+    (or_exit(url).to_string(), NetworkSettings::default())
+  } else {
+    let config = or_exit(Config::load(&Client::config_path(config_path), profile));
+    (or_exit(config.api_url(global.value_of("api-url"))), config.network().clone())
+  };
+
+  let transport = http_transport(global, &network, &api_url);
+  let mut results = vec![];
+  let mut failure = None;
+  for path in matches.values_of("SIGNED").expect("SIGNED to be set") {
+    let submitted = std::fs::read_to_string(path)
+      .map_err(Error::from)
+      .and_then(|json| Ok(serde_json::from_str::<SignedPayload>(&json)?))
+      .and_then(|signed| Ok((Client::submit_to(&transport, &api_url, &signed)?, signed)));
+
+    match submitted {
+      Ok((document, signed)) => {
+        if let Err(err) = ledger.record(&LedgerEntry::for_signed_payload(path, &signed, &document)) {
+          eprintln!("{} {} was stamped as document {}, but it could not be recorded in {}: {}", Emoji("⚠️", "WARNING:"), path, document.id, ledger.path().display(), err);
+        }
+        results.push(Submitted { path: path.to_string(), document: Some(document), error: None });
+      },
+      Err(err) => {
+        eprintln!("{} {}: {}\n", Emoji("🚨", "*"), path, err);
+        failure = failure.or_else(|| Some(exit_code::for_error(&err)));
+        results.push(Submitted { path: path.to_string(), document: None, error: Some(err.to_string()) });
+      },
+    }
+  }
+
+  out.print(&results, |results| display::submitted(results));
 
-  format!("\
-    We have started the validation process for {}.\n\
-    To verify you manage {} we need you to create a file at:\n\
-    {}/constata_eu_domain_verification.txt\n\
-    The file contents should be:\n\
-    {}
-  ", website, website, website, signature)
+  if let Some(code) = failure {
+    std::process::exit(code);
+  }
+}
+
+fn verify_website_flow(out: &Output, client: &Client, website: &str) {
+  let (_response, signature) = or_exit(client.verify_website(website.as_bytes()));
+
+  out.print(
+    &serde_json::json!({
+      "website": website,
+      "verification_file_url": format!("{}/constata_eu_domain_verification.txt", website),
+      "verification_file_contents": signature,
+    }),
+    |_| display::website_verification_started(website, &signature),
+  );
 }
 
-fn create_config_file() {
-  println!("\
+fn create_config_file(out: &Output, config_path: Option<&str>, profile: Option<&str>, env: Option<&str>) {
+  out.say("\
     You authenticate to our API by signing your requests with your own digital signature.\n\
     This tool will create a private key for you and store it locally on a file in your local drive.\n\
     You can optionally encrypt your key with a daily password, so that anyone with access to the file can't use it.\n\
@@ -199,40 +876,41 @@ fn create_config_file() {
     A master seed is composed of 12 words that you should keep safe,\n\
     and a password that you should remember.\n\
     Should your private key be compromised on its daily usage, you can use your master seed to\n\
-    invalidate your compromised key.\n\
+    replace it with a new key by running `constata-cli rotate-key`, which also signs a statement announcing the change.\n\
+    Our servers keep accepting the compromised key until you email that statement to hola@constata.eu.\n\
     Since you don't use your master seed on a daily basis, it shouldn't be subject to be compromised so often.\n\
     Nevertheless, you should never reveal your seed, and make sure you can remember your seed password.\n\
     We suggest you write the master seed words in paper and store them safely.\n\
   ");
 
-  let daily_pass = Password::with_theme(&ColorfulTheme::default())
+  let daily_pass = or_exit(Password::with_theme(&ColorfulTheme::default())
     .with_prompt("Type a daily password")
     .with_confirmation("Repeat password", "Error: the passwords don't match.")
     .interact()
-    .unwrap();
+    .map_err(Error::from));
 
-  let backup_pass = Password::with_theme(&ColorfulTheme::default())
+  let backup_pass = or_exit(Password::with_theme(&ColorfulTheme::default())
     .with_prompt("Type a different password for the master seed")
     .with_confirmation("Repeat password", "Error: the passwords don't match.")
     .interact()
-    .unwrap();
+    .map_err(Error::from));
 
-  let words = Client::create(None, None, &backup_pass, &daily_pass).unwrap();
+  let words = or_exit(Client::create(config_path, profile, env, &backup_pass, &daily_pass));
 
-  println!(
+  out.say(&format!(
     "\
-    A file has been created in {} with your private key\n\
+    Your private key has been saved in {}\n\
     encrypted with your daily password.\n",
-    Client::config_path(None)
-  );
+    Client::config_path(config_path)
+  ));
 
-  println!(
+  out.say(
     "\
     Now, we need you to write down these words on paper, in the order they're presented.\n\
     They are your master seed. Don't write down your master seed password though."
   );
 
   for (i, word) in words.iter().enumerate() {
-    println!("{:02}. {}", i + 1, word);
+    out.say(&format!("{:02}. {}", i + 1, word));
   }
 }
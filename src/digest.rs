@@ -0,0 +1,92 @@
+use super::*;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/* What gets signed when stamping only a hash: a statement about the file
+ * instead of the file itself, so its contents never leave this machine.
+ * Fields are declared in alphabetical order and metadata is a sorted map,
+ * which makes serde_json's compact output a canonical encoding.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DigestStatement {
+  pub constata_eu_action: String,
+  pub file_name: String,
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  pub metadata: BTreeMap<String, String>,
+  pub sha256: String,
+  pub size: u64,
+}
+
+pub const STAMP_DIGEST_ACTION: &str = "stamp_digest";
+
+impl DigestStatement {
+  pub fn new(file_name: &str, bytes: &[u8], metadata: BTreeMap<String, String>) -> DigestStatement {
+    DigestStatement {
+      constata_eu_action: STAMP_DIGEST_ACTION.to_string(),
+      file_name: file_name.to_string(),
+      metadata,
+      sha256: signed_payload::hexdigest(bytes),
+      size: bytes.len() as u64,
+    }
+  }
+
+  pub fn for_file(path: &str, metadata: BTreeMap<String, String>) -> Result<DigestStatement> {
+    let bytes = read_document_file(path)?;
+    let file_name = Path::new(path).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    Ok(Self::new(&file_name, &bytes, metadata))
+  }
+
+  pub fn to_canonical_json(&self) -> Vec<u8> {
+    serde_json::to_vec(self).expect("digest statements to always serialize")
+  }
+
+  /* The statement in a signed payload, if it's a hash only stamp. */
+  pub fn from_payload(payload: &[u8]) -> Option<DigestStatement> {
+    serde_json::from_slice::<DigestStatement>(payload)
+      .ok()
+      .filter(|s| s.constata_eu_action == STAMP_DIGEST_ACTION)
+  }
+
+  pub fn matches(&self, bytes: &[u8]) -> bool {
+    self.size == bytes.len() as u64 && self.sha256 == signed_payload::hexdigest(bytes)
+  }
+}
+
+impl Client {
+  pub fn sign_digest_path(&self, path: &str, metadata: BTreeMap<String, String>) -> Result<signed_payload::SignedPayload> {
+    Ok(self.sign(&DigestStatement::for_file(path, metadata)?.to_canonical_json()))
+  }
+
+  /* Like sign_and_timestamp_path, but only the file's digest statement is sent. */
+  pub fn stamp_digest_path(&self, path: &str, metadata: BTreeMap<String, String>) -> Result<DocumentBundle> {
+    let statement = DigestStatement::for_file(path, metadata)?;
+    let document = self.submit(&self.sign(&statement.to_canonical_json()))?;
+    self.record_in_ledger(path, &statement.sha256, &document);
+    Ok(document)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn serializes_statements_canonically() {
+    let mut metadata = BTreeMap::new();
+    metadata.insert("matter".to_string(), "42".to_string());
+    metadata.insert("author".to_string(), "Legal".to_string());
+    let statement = DigestStatement::new("contract.pdf", b"confidential", metadata);
+
+    assert_eq!(
+      String::from_utf8(statement.to_canonical_json()).unwrap(),
+      format!(
+        r#"{{"constata_eu_action":"stamp_digest","file_name":"contract.pdf","metadata":{{"author":"Legal","matter":"42"}},"sha256":"{}","size":12}}"#,
+        signed_payload::hexdigest(b"confidential")
+      )
+    );
+    assert_eq!(DigestStatement::from_payload(&statement.to_canonical_json()), Some(statement.clone()));
+    assert_eq!(DigestStatement::from_payload(b"confidential"), None);
+    assert!(statement.matches(b"confidential"));
+    assert!(!statement.matches(b"confidentiaL"));
+  }
+}
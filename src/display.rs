@@ -0,0 +1,275 @@
+/* Renders library results for humans in a terminal.
+ * The library itself never prints, these helpers only build strings.
+ */
+use constata_client_lib::{
+  batch::{BatchEntry, BatchOutcome},
+  ledger::{LedgerEntry, LedgerSync},
+  network::NetworkSettings,
+  profiles::Profiles,
+  verify::Verification,
+  AccountState, DocumentBundle, PubkeyDomainEndorsement, PubkeyDomainEndorsementState,
+};
+use crate::output::{Proof, Submitted, VerifyResult};
+use constata_client_lib::streaming::Phase;
+use dialoguer::console::{style, Emoji};
+use indicatif::{ProgressBar, ProgressStyle};
+
+pub fn field<T: std::fmt::Display>(label: &str, value: T) -> String {
+  format!("{} {}", style(label).bold().bright(), value)
+}
+
+pub fn stamped_document(document: &DocumentBundle) -> String {
+  let mut lines = vec![
+    field("Document state:", format!("{:?}", document.state)),
+    field("Document id:", &document.id),
+  ];
+  if let Some(bulletin_id) = document.bulletin_id {
+    lines.push(field("Bulletin id:", bulletin_id));
+  }
+  lines.push(field("Cost:", document.cost));
+  lines.push(field("Created At:", document.created_at));
+  if let Some(link) = &document.buy_tokens_link {
+    lines.push(field("Buy token link:", link));
+  }
+  lines.join("\n")
+}
+
+/* Drawn on stderr, and only when it's a terminal. */
+pub fn progress_bar(size: u64) -> ProgressBar {
+  let bar = ProgressBar::new(size);
+  bar.set_style(ProgressStyle::default_bar().template("{msg:10} [{bar:40}] {bytes}/{total_bytes} ({eta})"));
+  bar
+}
+
+pub fn update_progress(bar: &ProgressBar, phase: Phase, done: u64) {
+  bar.set_message(match phase {
+    Phase::Hashing => "Signing",
+    Phase::Uploading => "Uploading",
+  });
+  bar.set_position(done);
+}
+
+pub fn download_progress(bar: &ProgressBar, done: u64, total: Option<u64>) {
+  bar.set_length(total.unwrap_or(done));
+  bar.set_message("Downloading");
+  bar.set_position(done);
+}
+
+pub fn document(document: &DocumentBundle) -> String {
+  let mut lines = vec![
+    field("Document state:", format!("{:?}", document.state)),
+    field("Document id:", &document.id),
+  ];
+  if let Some(bulletin_id) = document.bulletin_id {
+    lines.push(field("Bulletin id:", bulletin_id));
+  }
+  if let Some(bulletin) = document.bulletin() {
+    lines.push(field("Bulletin state:", format!("{:?}", bulletin.state)));
+  }
+  lines.push(field("Cost:", document.cost));
+  lines.push(field("Created At:", document.created_at));
+  lines.join("\n")
+}
+
+pub fn document_transition(document: &DocumentBundle) -> String {
+  match document.bulletin() {
+    Some(bulletin) => format!(
+      "{} {:?} / {} {:?}",
+      style("Document state:").bold().bright(), document.state,
+      style("Bulletin state:").bold().bright(), bulletin.state,
+    ),
+    None => field("Document state:", format!("{:?}", document.state)),
+  }
+}
+
+pub fn document_list(documents: &[DocumentBundle]) -> String {
+  let mut lines = vec![
+    format!("{} {}", Emoji("📑", "*"), field("Total Documents:", documents.len())),
+    style("Document ID / Bulletin ID:").bold().bright().to_string(),
+  ];
+  for document in documents {
+    match document.bulletin_id {
+      Some(bulletin_id) => lines.push(format!("  {} / {}", document.id, bulletin_id)),
+      None => lines.push(format!("  {} / -", document.id)),
+    }
+  }
+  lines.join("\n")
+}
+
+pub fn batch_results(entries: &[BatchEntry], manifest: &str) -> String {
+  let count = |outcome: BatchOutcome| entries.iter().filter(|e| e.outcome == outcome).count();
+  let mut lines = vec![];
+  for entry in entries {
+    match entry.outcome {
+      BatchOutcome::Stamped =>
+        lines.push(format!("  {} {}", entry.document_id.as_deref().unwrap_or("-"), entry.path)),
+      BatchOutcome::AlreadyStamped =>
+        lines.push(format!("  {} {} (already stamped)", entry.document_id.as_deref().unwrap_or("-"), entry.path)),
+      BatchOutcome::Failed =>
+        lines.push(format!("  {} {} {}", Emoji("🚨", "*"), entry.path, entry.error.as_deref().unwrap_or(""))),
+    }
+  }
+  lines.push(field("Stamped:", count(BatchOutcome::Stamped)));
+  lines.push(field("Already stamped:", count(BatchOutcome::AlreadyStamped)));
+  lines.push(field("Failed:", count(BatchOutcome::Failed)));
+  lines.push(field("Manifest:", manifest));
+  lines.join("\n")
+}
+
+pub fn submitted(results: &[Submitted]) -> String {
+  results
+    .iter()
+    .filter_map(|result| result.document.as_ref().map(|document| {
+      format!("{}\n{}\n", field("Submitted:", &result.path), stamped_document(document))
+    }))
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+pub fn ledger_entry(entry: &LedgerEntry) -> String {
+  let mut lines = vec![
+    field("Path:", &entry.path),
+    field("Sha256:", &entry.sha256),
+    field("Document id:", &entry.document_id),
+    field("Document state:", format!("{:?}", entry.state)),
+  ];
+  if let Some(bulletin_id) = entry.bulletin_id {
+    lines.push(field("Bulletin id:", bulletin_id));
+  }
+  lines.push(field("Recorded At:", entry.recorded_at));
+  lines.join("\n")
+}
+
+pub fn history(entries: &[LedgerEntry]) -> String {
+  let mut lines = vec![
+    format!("{} {}", Emoji("📑", "*"), field("Ledger entries:", entries.len())),
+    style("Recorded At / Document ID / State / Path:").bold().bright().to_string(),
+  ];
+  for entry in entries {
+    lines.push(format!("  {} / {} / {:?} / {}", entry.recorded_at, entry.document_id, entry.state, entry.path));
+  }
+  lines.join("\n")
+}
+
+pub fn synced(sync: &LedgerSync) -> String {
+  let mut lines = vec![field("Documents updated:", sync.changed.len())];
+  for entry in &sync.changed {
+    lines.push(format!("  {} is now {:?}", entry.document_id, entry.state));
+  }
+  if !sync.failed.is_empty() {
+    lines.push(field("Documents that could not be refreshed:", sync.failed.len()));
+    for failure in &sync.failed {
+      lines.push(format!("  {} ({}): {}", failure.document_id, failure.path, failure.error));
+    }
+  }
+  lines.join("\n")
+}
+
+pub fn profiles(profiles: &Profiles, path: &str) -> String {
+  let default = profiles.name(None);
+  let mut lines = vec![field("Config file:", path)];
+  for (name, config) in &profiles.profiles {
+    let marker = if name == default { "*" } else { " " };
+    lines.push(format!("{} {} ({})", marker, name, config.public_key()));
+  }
+  lines.join("\n")
+}
+
+fn check(ok: bool) -> Emoji<'static, 'static> {
+  if ok { Emoji("✅", "ok") } else { Emoji("❌", "FAILED") }
+}
+
+pub fn network(settings: &NetworkSettings) -> String {
+  if settings.is_empty() {
+    return "No proxy or TLS settings stored in this profile".to_string();
+  }
+  let unset = || "-".to_string();
+  [
+    field("Proxy:", settings.proxy.as_deref().map(without_credentials).unwrap_or_else(unset)),
+    field("CA certificates:", settings.ca_cert.clone().unwrap_or_else(unset)),
+    field("Client certificate:", settings.client_cert.clone().unwrap_or_else(unset)),
+    field("Client key:", settings.client_key.clone().unwrap_or_else(unset)),
+  ].join("\n")
+}
+
+pub fn without_credentials(url: &str) -> String {
+  match (url.find("://"), url.rfind('@')) {
+    (Some(scheme_end), Some(at)) if at > scheme_end => format!("{}***{}", &url[..scheme_end + 3], &url[at..]),
+    _ => url.to_string(),
+  }
+}
+
+pub fn verification(verification: &Verification) -> String {
+  let mut lines = vec![
+    field("File sha256:", &verification.sha256),
+    format!(
+      "{} {}",
+      check(verification.hash_matches),
+      if verification.hash_only { "File matches the signed digest statement" } else { "File matches the signed payload" },
+    ),
+    format!("{} {} {}", check(verification.signature_valid), "Signature is valid for", verification.signer),
+  ];
+  match verification.signer_matches {
+    Some(matches) => lines.push(format!("{} {}", check(matches), "Signer is the expected public key")),
+    None => lines.push(format!("{} {}", Emoji("➖", "-"), "Signer was not checked, use --public-key or --own-key to check it")),
+  }
+  lines.join("\n")
+}
+
+pub fn verify_result(result: &VerifyResult) -> String {
+  let mut sections = vec![];
+  if let Some(signature) = &result.signature {
+    sections.push(verification(signature));
+  }
+  if let Some(proof) = &result.proof {
+    sections.push(proof_verification(&proof.path, proof.referenced));
+  }
+  sections.join("\n")
+}
+
+pub fn proof_verification(proof: &str, referenced: bool) -> String {
+  format!("{} {} {}", check(referenced), "File is one of the parts covered by", proof)
+}
+
+pub fn account_state(state: &AccountState) -> String {
+  [
+    field("Token balance:", state.token_balance),
+    field("Tokens missing:", state.missing),
+    field("Parked documents:", state.parked_count),
+    field("Total documents:", state.total_document_count),
+  ].join("\n")
+}
+
+pub fn saved_proof(proof: &Proof) -> String {
+  let path = proof.path.as_deref().unwrap_or("-");
+  if proof.files.is_empty() {
+    return field("Proof saved to:", path);
+  }
+  let mut lines = vec![field("Proofs extracted to:", path)];
+  for file in &proof.files {
+    lines.push(format!("  {}", file));
+  }
+  lines.join("\n")
+}
+
+pub fn website_verifications(sites: &[PubkeyDomainEndorsement]) -> String {
+  let mut lines = vec![];
+  for site in sites {
+    lines.push(field("Site:", &site.domain));
+    lines.push(field("Verification state:", format!("{:?}", site.state)));
+    if site.state != PubkeyDomainEndorsementState::Accepted {
+      lines.push(format!("{}\n", field("Attempts:", site.attempts)));
+    }
+  }
+  lines.join("\n")
+}
+
+pub fn website_verification_started(website: &str, signature: &str) -> String {
+  format!("\
+    We have started the validation process for {}.\n\
+    To verify you manage {} we need you to create a file at:\n\
+    {}/constata_eu_domain_verification.txt\n\
+    The file contents should be:\n\
+    {}
+  ", website, website, website, signature)
+}
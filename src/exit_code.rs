@@ -0,0 +1,93 @@
+/* Process exit codes. Scripts rely on them, so a code never changes its meaning.
+ * They're listed in `constata-cli --help` and in the README.
+ */
+use constata_client_lib::Error;
+use std::io::ErrorKind;
+
+/* Anything not covered below, like a file that doesn't match its signature or proof. */
+pub const FAILURE: i32 = 1;
+/* Invalid arguments or settings. */
+pub const USAGE: i32 = 2;
+/* No config or password, a wrong password, or the API rejected our credentials. */
+pub const AUTH: i32 = 3;
+/* A file, profile or document that doesn't exist. */
+pub const NOT_FOUND: i32 = 4;
+pub const ALREADY_STAMPED: i32 = 5;
+/* The API could not be reached. */
+pub const NETWORK: i32 = 6;
+/* The API answered with an error. */
+pub const SERVER_ERROR: i32 = 7;
+/* The document is not confirmed on Bitcoin yet. */
+pub const PENDING: i32 = 8;
+
+pub const HELP: &str = "\
+EXIT CODES:
+    0    Success
+    1    Failure not covered below, like a file that doesn't verify
+    2    Invalid arguments or settings
+    3    Missing config or password, wrong password, or credentials rejected by the API
+    4    File, profile or document not found
+    5    Document already stamped
+    6    Could not reach the API
+    7    The API answered with an error
+    8    Document not confirmed on Bitcoin yet";
+
+pub fn for_error(err: &Error) -> i32 {
+  match err {
+    Error::PasswordMissing
+    | Error::WrongPassword
+    | Error::DailyKeyEncriptionError
+    | Error::ConfigKeyMismatch
+    | Error::SeedMismatch => AUTH,
+    Error::UnknownEnvironment(_) | Error::InvalidApiUrl(_) | Error::Glob(_) | Error::NoSecretStore => USAGE,
+    Error::FileNotFound(_) | Error::ProfileNotFound(_) | Error::IsADirectory(_) => NOT_FOUND,
+    Error::AlreadyStamped { .. } => ALREADY_STAMPED,
+    Error::NotYetConfirmed { .. } => PENDING,
+    Error::Network(_) | Error::InvalidProxy(_) | Error::Tls(_) | Error::Truncated { .. } => NETWORK,
+    #[cfg(any(feature = "async", feature = "blocking-reqwest"))]
+    Error::AsyncNetwork(err) => err.status().map_or(NETWORK, |status| for_status(status.as_u16())),
+    Error::Api { status, .. } => for_status(*status),
+    Error::Io(err) => match err.kind() {
+      ErrorKind::NotFound => NOT_FOUND,
+      ErrorKind::TimedOut
+      | ErrorKind::ConnectionRefused
+      | ErrorKind::ConnectionReset
+      | ErrorKind::ConnectionAborted
+      | ErrorKind::UnexpectedEof => NETWORK,
+      _ => FAILURE,
+    },
+    _ => FAILURE,
+  }
+}
+
+/* A 422 only means "already stamped" when creating a document, and those are
+ * turned into Error::AlreadyStamped where they're made. Anywhere else it's a rejected request.
+ */
+fn for_status(status: u16) -> i32 {
+  match status {
+    401 | 403 => AUTH,
+    404 => NOT_FOUND,
+    _ => SERVER_ERROR,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn maps_errors_to_exit_codes() {
+    assert_eq!(for_error(&Error::WrongPassword), AUTH);
+    assert_eq!(for_error(&Error::Api { status: 401, body: String::new() }), AUTH);
+    assert_eq!(for_error(&Error::Api { status: 404, body: String::new() }), NOT_FOUND);
+    assert_eq!(for_error(&Error::Api { status: 503, body: String::new() }), SERVER_ERROR);
+    assert_eq!(for_error(&Error::Api { status: 422, body: String::new() }), SERVER_ERROR);
+    assert_eq!(for_error(&Error::FileNotFound("a.pdf".to_string())), NOT_FOUND);
+    assert_eq!(for_error(&Error::AlreadyStamped { document_id: None }), ALREADY_STAMPED);
+    assert_eq!(for_error(&Error::NotYetConfirmed { document_id: "1-a".to_string() }), PENDING);
+    assert_eq!(for_error(&Error::InvalidApiUrl("ftp://x".to_string())), USAGE);
+    assert_eq!(for_error(&Error::Truncated { expected: 10, received: 3 }), NETWORK);
+    assert_eq!(for_error(&std::io::Error::new(ErrorKind::TimedOut, "slow").into()), NETWORK);
+    assert_eq!(for_error(&std::io::Error::new(ErrorKind::PermissionDenied, "no").into()), FAILURE);
+  }
+}
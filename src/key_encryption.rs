@@ -0,0 +1,106 @@
+use super::*;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+  aead::{Aead, KeyInit},
+  ChaCha20Poly1305, Key, Nonce,
+};
+
+/* How the signing key in a version 2 config is encrypted.
+ * The daily passphrase is stretched with Argon2id into a ChaCha20-Poly1305 key.
+ * Salt, nonce and cost parameters are stored so they can be raised later
+ * without breaking existing configs.
+ */
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyEncryption {
+  pub kdf: String,
+  pub m_cost: u32,
+  pub t_cost: u32,
+  pub p_cost: u32,
+  #[serde_as(as = "serde_with::hex::Hex")]
+  pub salt: Vec<u8>,
+  pub cipher: String,
+  #[serde_as(as = "serde_with::hex::Hex")]
+  pub nonce: Vec<u8>,
+}
+
+/* Argon2id costs for new encryptions: 64 MiB of memory and 3 passes.
+ * The argon2 crate defaults to 4 MiB, too little for a key guarding a wallet seed.
+ */
+pub const M_COST: u32 = 64 * 1024;
+pub const T_COST: u32 = 3;
+pub const P_COST: u32 = 1;
+
+impl KeyEncryption {
+  pub fn encrypt(plaintext: &[u8], passphrase: &str) -> Result<(Vec<u8>, KeyEncryption)> {
+    let params = KeyEncryption {
+      kdf: "argon2id".to_string(),
+      m_cost: M_COST,
+      t_cost: T_COST,
+      p_cost: P_COST,
+      salt: rand::random::<[u8; 16]>().to_vec(),
+      cipher: "chacha20poly1305".to_string(),
+      nonce: rand::random::<[u8; 12]>().to_vec(),
+    };
+
+    let ciphertext = params
+      .cipher(passphrase)?
+      .encrypt(Nonce::from_slice(&params.nonce), plaintext)
+      .map_err(|_| Error::DailyKeyEncriptionError)?;
+
+    Ok((ciphertext, params))
+  }
+
+  /* Whether this was encrypted with lower costs than new encryptions use. */
+  pub fn is_outdated(&self) -> bool {
+    self.m_cost < M_COST || self.t_cost < T_COST
+  }
+
+  pub fn decrypt(&self, ciphertext: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+    if self.kdf != "argon2id" || self.cipher != "chacha20poly1305" || self.nonce.len() != 12 {
+      return Err(Error::DailyKeyEncriptionError);
+    }
+
+    self
+      .cipher(passphrase)?
+      .decrypt(Nonce::from_slice(&self.nonce), ciphertext)
+      .map_err(|_| Error::WrongPassword)
+  }
+
+  fn cipher(&self, passphrase: &str) -> Result<ChaCha20Poly1305> {
+    let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32))
+      .map_err(|_| Error::DailyKeyEncriptionError)?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+      .hash_password_into(passphrase.as_bytes(), &self.salt, &mut key)
+      .map_err(|_| Error::DailyKeyEncriptionError)?;
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn encrypts_and_decrypts_with_long_passphrases() {
+    let long = "a passphrase that is way longer than thirty two characters";
+    let (ciphertext, params) = KeyEncryption::encrypt(b"secret key", long).unwrap();
+
+    assert_eq!(params.decrypt(&ciphertext, long).unwrap(), b"secret key".to_vec());
+    assert!(matches!(params.decrypt(&ciphertext, &long[..32]), Err(Error::WrongPassword)));
+  }
+
+  #[test]
+  fn stores_its_argon2_costs() {
+    let (ciphertext, params) = KeyEncryption::encrypt(b"secret key", "not_so_secret").unwrap();
+    assert_eq!((params.m_cost, params.t_cost, params.p_cost), (64 * 1024, 3, 1));
+    assert!(!params.is_outdated());
+
+    let stored: KeyEncryption = serde_json::from_value(serde_json::to_value(&params).unwrap()).unwrap();
+    assert_eq!(stored.decrypt(&ciphertext, "not_so_secret").unwrap(), b"secret key".to_vec());
+
+    let weaker = KeyEncryption { m_cost: Params::DEFAULT_M_COST, ..params };
+    assert!(weaker.is_outdated());
+  }
+}
@@ -0,0 +1,367 @@
+use super::*;
+use chrono::{DateTime, Utc};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/* The ledger remembers what this machine stamped.
+ * It's a JSON lines file living next to the config file. Entries are only
+ * ever appended, the latest entry for a document id holds its current state.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LedgerEntry {
+  pub path: String,
+  pub sha256: String,
+  pub document_id: String,
+  pub bulletin_id: Option<i32>,
+  pub state: DocumentState,
+  pub recorded_at: DateTime<Utc>,
+}
+
+impl LedgerEntry {
+  pub fn new(path: &str, sha256: &str, document: &DocumentBundle) -> LedgerEntry {
+    LedgerEntry {
+      path: path.to_string(),
+      sha256: sha256.to_string(),
+      document_id: document.id.clone(),
+      bulletin_id: document.bulletin_id,
+      state: document.state,
+      recorded_at: Utc::now(),
+    }
+  }
+
+  /* The entry for a signed payload submitted from `signed_path`, about the document it stamps.
+   * Hash only stamps name the document and its sha256 in their statement. Other payloads
+   * are the document itself, which `sign` saves them next to as <FILE>.signed.json.
+   */
+  pub fn for_signed_payload(signed_path: &str, signed: &signed_payload::SignedPayload, document: &DocumentBundle) -> LedgerEntry {
+    match digest::DigestStatement::from_payload(&signed.payload) {
+      Some(statement) => LedgerEntry::new(&statement.file_name, &statement.sha256, document),
+      None => {
+        let path = signed_path.strip_suffix(".signed.json").unwrap_or(signed_path);
+        LedgerEntry::new(path, &signed.payload_hash(), document)
+      },
+    }
+  }
+}
+
+/* Documents whose state changed, and the ones that could not be refreshed. */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LedgerSync {
+  pub changed: Vec<LedgerEntry>,
+  pub failed: Vec<SyncFailure>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SyncFailure {
+  pub document_id: String,
+  pub path: String,
+  pub error: String,
+}
+
+/* Clones share their lock and warnings, so batch workers can record through the same ledger at once. */
+#[derive(Debug, Clone)]
+pub struct Ledger {
+  path: PathBuf,
+  writing: Arc<Mutex<()>>,
+  warnings: Arc<Mutex<Vec<String>>>,
+}
+
+impl Ledger {
+  pub fn new<P: AsRef<Path>>(path: P) -> Ledger {
+    Ledger { path: path.as_ref().to_path_buf(), writing: Arc::new(Mutex::new(())), warnings: Arc::new(Mutex::new(vec![])) }
+  }
+
+  /* Each profile gets its own ledger, since its documents belong to a different key.
+   * The profile is resolved like Profiles::name does, so leaving out --profile
+   * uses the same ledger as naming the default profile.
+   */
+  pub fn for_config(config_path: &str, profile: Option<&str>) -> Ledger {
+    let profiles = profiles::Profiles::load_or_default(config_path).unwrap_or_default();
+    let name = match profiles.name(profile) {
+      profiles::DEFAULT_PROFILE => "constata_ledger.jsonl".to_string(),
+      profile => format!("constata_ledger.{}.jsonl", profile),
+    };
+    Ledger::new(Path::new(config_path).with_file_name(name))
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  pub fn entries(&self) -> Result<Vec<LedgerEntry>> {
+    let contents = match std::fs::read_to_string(&self.path) {
+      Ok(contents) => contents,
+      Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+      Err(e) => return Err(e.into()),
+    };
+
+    contents
+      .lines()
+      .filter(|line| !line.trim().is_empty())
+      .map(|line| Ok(serde_json::from_str(line)?))
+      .collect()
+  }
+
+  /* The whole line goes in a single write, so concurrent entries never interleave. */
+  pub fn record(&self, entry: &LedgerEntry) -> Result<()> {
+    let line = format!("{}\n", serde_json::to_string(entry)?);
+    let _writing = self.writing.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+    file.write_all(line.as_bytes())?;
+    Ok(())
+  }
+
+  /* The most recent entry of each document, in the order they were first stamped. */
+  pub fn latest(&self) -> Result<Vec<LedgerEntry>> {
+    let mut latest: Vec<LedgerEntry> = vec![];
+    for entry in self.entries()? {
+      match latest.iter_mut().find(|e| e.document_id == entry.document_id) {
+        Some(existing) => *existing = entry,
+        None => latest.push(entry),
+      }
+    }
+    Ok(latest)
+  }
+
+  pub fn find_by_hash(&self, sha256: &str) -> Result<Option<LedgerEntry>> {
+    Ok(self.latest()?.into_iter().find(|e| e.sha256 == sha256))
+  }
+
+  pub fn find_file(&self, path: &str) -> Result<Option<LedgerEntry>> {
+    self.find_by_hash(&signed_payload::hexdigest(&read_document_file(path)?))
+  }
+
+  /* Stamps that could not be recorded, since they were last taken.
+   * The documents were stamped anyway, so these are only warnings to show the user.
+   */
+  pub fn take_warnings(&self) -> Vec<String> {
+    std::mem::take(&mut *self.warnings.lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
+  }
+}
+
+impl Client {
+  pub fn ledger(&self) -> Option<&Ledger> {
+    self.ledger.as_ref()
+  }
+
+  /* The document was already created and paid for, so failing to record it must not lose it.
+   * The failure is kept for Ledger::take_warnings instead.
+   */
+  pub(crate) fn record_in_ledger(&self, path: &str, sha256: &str, document: &DocumentBundle) {
+    if let Some(ledger) = &self.ledger {
+      if let Err(err) = ledger.record(&LedgerEntry::new(path, sha256, document)) {
+        let warning = format!(
+          "{} was stamped as document {}, but it could not be recorded in {}: {}",
+          path, document.id, ledger.path.display(), err
+        );
+        ledger.warnings.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(warning);
+      }
+    }
+  }
+
+  /* Refreshes every document in the ledger, recording the ones whose state changed.
+   * A document that can't be fetched is reported in `failed` and the rest still sync.
+   */
+  pub fn sync_ledger(&self) -> Result<LedgerSync> {
+    let mut sync = LedgerSync { changed: vec![], failed: vec![] };
+    let ledger = match &self.ledger {
+      Some(ledger) => ledger,
+      None => return Ok(sync),
+    };
+
+    for entry in ledger.latest()? {
+      let document = match self.document(&entry.document_id) {
+        Ok(document) => document,
+        Err(err) => {
+          sync.failed.push(SyncFailure { document_id: entry.document_id, path: entry.path, error: err.to_string() });
+          continue;
+        },
+      };
+      if document.state != entry.state || document.bulletin_id != entry.bulletin_id {
+        let updated = LedgerEntry::new(&entry.path, &entry.sha256, &document);
+        ledger.record(&updated)?;
+        sync.changed.push(updated);
+      }
+    }
+    Ok(sync)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use mockito;
+
+  fn document_json(id: &str, state: &str, bulletin_id: &str) -> String {
+    format!(
+      r#"{{"state":"{}","id":"{}","person_id":1,"bulletin_id":{},"parts":[],"created_at":"2022-01-05T08:04:47.166681Z","cost":"1","gift_id":null,"bulletins":{{}},"buy_tokens_link":null}}"#,
+      state, id, bulletin_id
+    )
+  }
+
+  fn client_with(ledger: &Ledger) -> Client {
+    let (config, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    let signature = Signature::load(config, "not_so_secret").unwrap();
+    Client { signature, api_url: mockito::server_url(), ledger: Some(ledger.clone()), transport: transport::default_transport() }
+  }
+
+  #[test]
+  fn records_stamps_and_syncs_their_state() {
+    let dir = std::env::temp_dir().join(format!("constata_ledger_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("diploma.txt");
+    std::fs::write(&file, b"a diploma").unwrap();
+    let ledger = Ledger::for_config(dir.join("constata_conf.json").to_str().unwrap(), None);
+    assert_eq!(ledger.path(), dir.join("constata_ledger.jsonl"));
+
+    let client = client_with(&ledger);
+
+    let stamp = mockito::mock("POST", "/documents/")
+        .with_status(200)
+        .with_body(document_json("1-1", "Parked", "null"))
+        .expect(1)
+        .create();
+    client.sign_and_timestamp_path(file.to_str().unwrap()).unwrap();
+    stamp.assert();
+
+    let found = ledger.find_file(file.to_str().unwrap()).unwrap().unwrap();
+    assert_eq!(found.document_id, "1-1");
+    assert_eq!(found.state, DocumentState::Parked);
+    assert_eq!(found.sha256, signed_payload::hexdigest(b"a diploma"));
+
+    let show = mockito::mock("GET", "/documents/1-1")
+        .with_status(200)
+        .with_body(document_json("1-1", "Published", "3"))
+        .expect(1)
+        .create();
+    let sync = client.sync_ledger().unwrap();
+    show.assert();
+
+    assert_eq!(sync.changed.len(), 1);
+    assert!(sync.failed.is_empty());
+    assert_eq!(ledger.entries().unwrap().len(), 2);
+    let latest = ledger.latest().unwrap();
+    assert_eq!(latest.len(), 1);
+    assert_eq!(latest[0].state, DocumentState::Published);
+    assert_eq!(latest[0].bulletin_id, Some(3));
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn records_the_document_a_signed_payload_stamps() {
+    let client = client_with(&Ledger::new("unused.jsonl"));
+    let document = serde_json::from_str::<DocumentBundle>(&document_json("1-3", "Parked", "null")).unwrap();
+
+    let full = client.sign(b"a diploma");
+    let entry = LedgerEntry::for_signed_payload("out/diploma.txt.signed.json", &full, &document);
+    assert_eq!(entry.path, "out/diploma.txt");
+    assert_eq!(entry.sha256, signed_payload::hexdigest(b"a diploma"));
+
+    let statement = digest::DigestStatement::new("diploma.txt", b"a diploma", Default::default());
+    let hash_only = client.sign(&statement.to_canonical_json());
+    let entry = LedgerEntry::for_signed_payload("out/diploma.txt.signed.json", &hash_only, &document);
+    assert_eq!(entry.path, "diploma.txt");
+    assert_eq!(entry.sha256, signed_payload::hexdigest(b"a diploma"));
+  }
+
+  #[test]
+  fn keeps_the_stamped_document_when_the_ledger_cannot_be_written() {
+    let dir = std::env::temp_dir().join(format!("constata_ledger_unwritable_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("diploma.txt");
+    std::fs::write(&file, b"an unrecorded diploma").unwrap();
+    let ledger = Ledger::new(dir.join("missing_dir").join("constata_ledger.jsonl"));
+
+    let stamp = mockito::mock("POST", "/documents/")
+        .with_status(200)
+        .with_body(document_json("1-2", "Parked", "null"))
+        .expect(1)
+        .create();
+    let document = client_with(&ledger).sign_and_timestamp_path(file.to_str().unwrap()).unwrap();
+    stamp.assert();
+
+    assert_eq!(document.id, "1-2");
+    let warnings = ledger.take_warnings();
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].contains("1-2"));
+    assert!(ledger.take_warnings().is_empty());
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn uses_the_same_ledger_with_or_without_naming_the_default_profile() {
+    let dir = std::env::temp_dir().join(format!("constata_ledger_profiles_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config_path = dir.join("constata_conf.json");
+    let config_path = config_path.to_str().unwrap();
+
+    let (config, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    let mut profiles = profiles::Profiles::default();
+    profiles.set(Some("bot"), config.clone());
+    profiles.set(Some("personal"), config);
+    profiles.set_default("bot").unwrap();
+    profiles.save(config_path).unwrap();
+
+    let bot = dir.join("constata_ledger.bot.jsonl");
+    assert_eq!(Ledger::for_config(config_path, None).path(), bot);
+    assert_eq!(Ledger::for_config(config_path, Some("bot")).path(), bot);
+    assert_eq!(Ledger::for_config(config_path, Some("personal")).path(), dir.join("constata_ledger.personal.jsonl"));
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn keeps_syncing_after_a_document_fails() {
+    let dir = std::env::temp_dir().join(format!("constata_ledger_sync_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let ledger = Ledger::new(dir.join("constata_ledger.jsonl"));
+    let parked = |id: &str| serde_json::from_str::<DocumentBundle>(&document_json(id, "Parked", "null")).unwrap();
+    ledger.record(&LedgerEntry::new("gone.txt", "aa", &parked("1-gone"))).unwrap();
+    ledger.record(&LedgerEntry::new("kept.txt", "bb", &parked("1-kept"))).unwrap();
+
+    let gone = mockito::mock("GET", "/documents/1-gone")
+        .with_status(404)
+        .expect(1)
+        .create();
+    let kept = mockito::mock("GET", "/documents/1-kept")
+        .with_status(200)
+        .with_body(document_json("1-kept", "Published", "4"))
+        .expect(1)
+        .create();
+    let sync = client_with(&ledger).sync_ledger().unwrap();
+    gone.assert();
+    kept.assert();
+
+    assert_eq!(sync.failed.len(), 1);
+    assert_eq!(sync.failed[0].document_id, "1-gone");
+    assert_eq!(sync.changed.len(), 1);
+    assert_eq!(sync.changed[0].document_id, "1-kept");
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn concurrent_records_never_interleave() {
+    let dir = std::env::temp_dir().join(format!("constata_ledger_threads_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let ledger = Ledger::new(dir.join("constata_ledger.jsonl"));
+    let document = serde_json::from_str::<DocumentBundle>(&document_json("1-1", "Parked", "null")).unwrap();
+    let path = "a/long/path/".repeat(500);
+
+    std::thread::scope(|scope| {
+      for _ in 0..8 {
+        scope.spawn(|| {
+          for _ in 0..20 {
+            ledger.record(&LedgerEntry::new(&path, "aa", &document)).unwrap();
+          }
+        });
+      }
+    });
+    assert_eq!(ledger.entries().unwrap().len(), 160);
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
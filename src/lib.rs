@@ -1,15 +1,29 @@
+#[cfg(feature = "async")]
+pub mod async_client;
+pub mod batch;
+pub mod digest;
+pub mod key_encryption;
+pub mod ledger;
+pub mod models;
+pub mod network;
+pub mod password;
+pub mod profiles;
+pub mod proofs;
 pub mod signature;
 pub mod signed_payload;
-
+pub mod streaming;
+pub mod transport;
+pub mod verify;
+pub mod wait;
+
+#[cfg(feature = "async")]
+pub use async_client::AsyncClient;
+pub use models::*;
 use signature::Signature;
 
 use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
-use serde_json::{Number, Value};
 use bitcoin::PublicKey;
-use std::collections::HashMap;
-
-use dialoguer::console::{Emoji, style};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -27,75 +41,242 @@ pub enum Error {
   PrivateKey(#[from] bitcoin::util::key::Error),
   #[error(transparent)]
   Network(#[from] Box<ureq::Error>),
+  #[cfg(any(feature = "async", feature = "blocking-reqwest"))]
+  #[error(transparent)]
+  AsyncNetwork(#[from] reqwest::Error),
+  #[error(transparent)]
+  Glob(#[from] glob::PatternError),
   #[error("An error ocurred when encrypting or decrypting the daily key")]
   DailyKeyEncriptionError,
   #[error("The decrypted signing key does not match the expected one")]
   ConfigKeyMismatch,
+  #[error("No daily password given. Use --password-file, --password-stdin, CONSTATA_PASSWORD or `constata-cli password save`")]
+  PasswordMissing,
+  #[error("Password incorrect")]
+  WrongPassword,
+  #[error("Unknown environment {0}. Use one of production, staging or development, or configure an api_url")]
+  UnknownEnvironment(String),
+  #[error("{0} is not a valid API url, it should start with http:// or https://")]
+  InvalidApiUrl(String),
+  #[error("There is no profile named {0} in your config file")]
+  ProfileNotFound(String),
+  #[error("The master seed words and password don't match the key in your config")]
+  SeedMismatch,
+  #[error("This document was already stamped")]
+  AlreadyStamped { document_id: Option<String> },
+  #[error("{0} changed after it was signed, so it was not stamped. Try again once nothing is writing to it")]
+  FileChanged(String),
+  #[error("{0} is a directory. Stamping could only be applied on files. If you want to stamp an entire directory, consider compressing it into a zip file")]
+  IsADirectory(String),
+  #[error("File not found using path {0}")]
+  FileNotFound(String),
+  #[error("The API responded with status {status}: {body}")]
+  Api { status: u16, body: String },
+  #[error("{0} is not a valid proxy url")]
+  InvalidProxy(String),
+  #[error("TLS configuration error: {0}")]
+  Tls(String),
+  #[error("Could not use the OS keyring: {0}. Your daily password was not stored")]
+  Keyring(String),
+  #[error("There is no OS keyring to remember your daily password in. Build with the \"keyring\" feature, or set CONSTATA_SECRETS_FILE to keep it unencrypted in that file")]
+  NoSecretStore,
+  #[error("There is no recorded response for {0}")]
+  NoRecordedResponse(String),
+  #[error("Document {document_id} is not confirmed on Bitcoin yet")]
+  NotYetConfirmed { document_id: String },
+  #[error("The download was cut short, got {received} of {expected} bytes")]
+  Truncated { expected: u64, received: u64 },
+  #[error("The proof ZIP file is incomplete or invalid: {0}")]
+  Zip(#[from] zip::result::ZipError),
 }
 
-pub type Result<T> = std::result::Result<T, Error>;
-
-#[derive(Serialize, Deserialize)]
-struct AccountState {
-  missing: String,
-  parked_count: Number,
-  person_id: Number,
-  token_balance: String,
-  total_document_count: Number,
+impl From<ureq::Error> for Error {
+  fn from(err: ureq::Error) -> Self {
+    match err {
+      ureq::Error::StatusCode(status) => Error::Api { status, body: String::new() },
+      other => Error::Network(Box::new(other)),
+    }
+  }
 }
-#[derive(Serialize, Deserialize)]
-pub struct Bulletin {
-  id: i32,
-  state: String,
-  started_at: String,
-  hash: Option<String>,
-  transaction: Option<String>,
-  transaction_hash: Option<String>,
-  block_hash: Option<String>,
-  block_time: Option<String>,
+
+impl Error {
+  /* The API answers 422 when the payload was already stamped,
+   * and may include the existing document id in the body.
+   */
+  pub(crate) fn already_stamped(body: &str) -> Error {
+    Error::AlreadyStamped {
+      document_id: serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .and_then(|v| v.get("document_id").or_else(|| v.get("id")).cloned())
+        .and_then(|id| id.as_str().map(|i| i.to_string())),
+    }
+  }
 }
-#[derive(Serialize, Deserialize)]
-struct DocumentBundle {
-  bulletin_id: Option<Number>,
-  bulletins: HashMap<i64, Bulletin>,
-  cost: String,
-  created_at: String,
-  gift_id: Value,
-  id: String,
-  parts: Value,
-  person_id: Number,
-  state: String,
-  buy_tokens_link: Value,
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/* Version 1 configs had their key encrypted with simplestcrypt using the raw passphrase.
+ * Version 2 configs describe their key derivation and cipher in `encryption`.
+ */
+pub const CURRENT_CONFIG_VERSION: u32 = 2;
+
+/* Environments known to this client, and the API they talk to. */
+pub const ENVIRONMENTS: &[(&str, &str)] = &[
+  ("production", "https://api.constata.eu"),
+  ("staging", "https://api-staging.constata.eu"),
+  ("development", "http://localhost:8000"),
+];
+
+pub fn environment_url(env: &str) -> Result<&'static str> {
+  ENVIRONMENTS
+    .iter()
+    .find(|(name, _)| *name == env)
+    .map(|(_, url)| *url)
+    .ok_or_else(|| Error::UnknownEnvironment(env.to_string()))
 }
 
-#[derive(Serialize, Deserialize)]
-struct PubkeyDomainEndorsement {
-  attempts: Number,
-  attempts_log: String,
-  bulletin_id: Option<Number>,
-  domain: String,
-  evidence: Option<String>,
-  evidence_hash: Option<String>,
-  id: Number,
-  next_attempt: String,
-  pubkey_id: String,
-  request_signature: String,
-  state: String,
+fn validate_api_url(url: &str) -> Result<String> {
+  if url.starts_with("https://") || url.starts_with("http://") {
+    Ok(url.trim_end_matches('/').to_string())
+  } else {
+    Err(Error::InvalidApiUrl(url.to_string()))
+  }
 }
 
+fn legacy_config_version() -> u32 {
+  1
+}
 
 #[serde_as]
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Config {
+  #[serde(default = "legacy_config_version")]
+  version: u32,
   public_key: PublicKey,
   #[serde_as(as = "serde_with::hex::Hex")]
   encrypted_key: Vec<u8>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  encryption: Option<key_encryption::KeyEncryption>,
   environment: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  api_url: Option<String>,
+  #[serde(default)]
+  key_index: u32,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  registered_at: Option<chrono::DateTime<chrono::Utc>>,
+  #[serde(default, skip_serializing_if = "network::NetworkSettings::is_empty")]
+  network: network::NetworkSettings,
+}
+
+impl Config {
+  pub fn load(path: &str, profile: Option<&str>) -> Result<Config> {
+    Ok(profiles::Profiles::load(path)?.get(profile)?.clone())
+  }
+
+  /* Stores this config as the given profile, keeping every other profile in the file. */
+  pub fn save(&self, path: &str, profile: Option<&str>) -> Result<()> {
+    let mut profiles = profiles::Profiles::load_or_default(path)?;
+    profiles.set(profile, self.clone());
+    profiles.save(path)
+  }
+
+  /* Old config versions, and keys encrypted with lower Argon2 costs than we use now,
+   * are re-encrypted the next time the daily password is given.
+   */
+  pub fn needs_migration(&self) -> bool {
+    self.version < CURRENT_CONFIG_VERSION || self.encryption.as_ref().is_some_and(|e| e.is_outdated())
+  }
+
+  pub fn public_key(&self) -> PublicKey {
+    self.public_key
+  }
+
+  pub fn environment(&self) -> &str {
+    &self.environment
+  }
+
+  /* Whether this key was already sent to /signup. New and rotated keys start unregistered. */
+  pub fn is_registered(&self) -> bool {
+    self.registered_at.is_some()
+  }
+
+  pub fn mark_registered(&mut self) {
+    self.registered_at = Some(chrono::Utc::now());
+  }
+
+  pub fn network(&self) -> &network::NetworkSettings {
+    &self.network
+  }
+
+  pub fn set_network(&mut self, network: network::NetworkSettings) {
+    self.network = network;
+  }
+
+  pub fn set_api_url(&mut self, api_url: Option<&str>) -> Result<()> {
+    self.api_url = api_url.map(validate_api_url).transpose()?;
+    Ok(())
+  }
+
+  /* An explicit override wins over the api_url stored in the config,
+   * which wins over the url of a known environment.
+   * Custom environment names are only accepted along with an api_url.
+   */
+  pub fn api_url(&self, override_url: Option<&str>) -> Result<String> {
+    match override_url.or(self.api_url.as_deref()) {
+      Some(url) => validate_api_url(url),
+      None => Ok(environment_url(&self.environment)?.to_string()),
+    }
+  }
+}
+
+/* Writes a file only its owner can read, for configs and master seeds. */
+pub fn write_private_file<P: AsRef<std::path::Path>>(path: P, contents: &[u8]) -> Result<()> {
+  use std::io::Write;
+  let mut options = std::fs::OpenOptions::new();
+  options.write(true).create(true).truncate(true);
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+    options.mode(0o600);
+    if path.as_ref().exists() {
+      std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    }
+  }
+  options.open(path)?.write_all(contents)?;
+  Ok(())
+}
+
+/* Reads a file to stamp or verify. Missing files and directories get their own errors,
+ * any other I/O error is kept as is.
+ */
+pub fn read_document_file(path: &str) -> Result<Vec<u8>> {
+  if std::path::Path::new(path).is_dir() {
+    return Err(Error::IsADirectory(path.to_string()));
+  }
+  match std::fs::read(path) {
+    Ok(bytes) => Ok(bytes),
+    Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Err(Error::FileNotFound(path.to_string())),
+    Err(err) => Err(err.into()),
+  }
+}
+
+/* Like read_document_file, for callers that read the document in chunks. */
+pub(crate) fn open_document_file(path: &str) -> Result<std::fs::File> {
+  if std::path::Path::new(path).is_dir() {
+    return Err(Error::IsADirectory(path.to_string()));
+  }
+  match std::fs::File::open(path) {
+    Ok(file) => Ok(file),
+    Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Err(Error::FileNotFound(path.to_string())),
+    Err(err) => Err(err.into()),
+  }
 }
 
 pub struct Client {
   signature: Signature,
   api_url: String,
+  ledger: Option<ledger::Ledger>,
+  transport: Box<dyn transport::Transport>,
 }
 
 /* The Client knows about managing local secrets, the local filesystem,
@@ -106,124 +287,198 @@ pub struct Client {
 impl Client {
   pub fn create(
     custom_config: Option<&str>,
+    profile: Option<&str>,
     env: Option<&str>,
     backup_pass: &str,
     daily_pass: &str,
   ) -> Result<Vec<String>> {
-    let (config, mnemonic) =
-      Signature::create(env.unwrap_or("production"), backup_pass, daily_pass)?;
-    std::fs::write(
-      Self::config_path(custom_config),
-      serde_json::to_string(&config)?,
-    )?;
+    let env = env.unwrap_or("production");
+    environment_url(env)?;
+    let (config, mnemonic) = Signature::create(env, backup_pass, daily_pass)?;
+    config.save(&Self::config_path(custom_config), profile)?;
     Ok(mnemonic.iter().map(|x| x.into()).collect())
   }
 
-  pub fn config_needed(custom_config: Option<&str>) -> bool {
-    !std::path::Path::new(Self::config_path(custom_config)).exists()
+  pub fn recover(
+    custom_config: Option<&str>,
+    profile: Option<&str>,
+    env: Option<&str>,
+    words: &str,
+    backup_pass: &str,
+    daily_pass: &str,
+    key_index: u32,
+  ) -> Result<()> {
+    let path = Self::config_path(custom_config);
+    let env = env.unwrap_or("production");
+    environment_url(env)?;
+    if profiles::Profiles::load_or_default(&path)?.contains(profile) {
+      return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!("{} already has that profile", path)).into());
+    }
+    Signature::recover(env, words, backup_pass, daily_pass, key_index)?.save(&path, profile)
+  }
+
+  /* Moves the config to the next key derived from the master seed.
+   * The signed rotation statement is returned, but not sent anywhere: the old key
+   * stays valid on the server until the statement reaches constata.
+   */
+  pub fn rotate_key(
+    custom_config: Option<&str>,
+    profile: Option<&str>,
+    words: &str,
+    backup_pass: &str,
+    new_daily_pass: &str,
+  ) -> Result<signed_payload::SignedPayload> {
+    let path = Self::config_path(custom_config);
+    let (rotated, statement) = Signature::rotate(&Config::load(&path, profile)?, words, backup_pass, new_daily_pass)?;
+    rotated.save(&path, profile)?;
+    Ok(statement)
   }
 
-  pub fn config_path(custom_config: Option<&str>) -> &str {
-    custom_config.unwrap_or("constata_conf.json")
+  pub fn change_password(
+    custom_config: Option<&str>,
+    profile: Option<&str>,
+    old_daily_pass: &str,
+    new_daily_pass: &str,
+  ) -> Result<()> {
+    let path = Self::config_path(custom_config);
+    Signature::change_password(Config::load(&path, profile)?, old_daily_pass, new_daily_pass)?.save(&path, profile)
   }
 
-  pub fn load(custom_config: Option<&str>, daily_passphrase: &str) -> Result<Client> {
-    let stored: Config =
-      serde_json::from_str(&std::fs::read_to_string(Self::config_path(custom_config))?)?;
-    let api_url = match stored.environment.as_str() {
-      "staging" => "https://api-staging.constata.eu",
-      "production" => "https://api.constata.eu",
-      _ => "http://localhost:8000",
+  /* Older config versions are upgraded in place the first time they're unlocked. */
+  pub(crate) fn load_config(custom_config: Option<&str>, profile: Option<&str>, daily_passphrase: &str) -> Result<Config> {
+    let path = Self::config_path(custom_config);
+    let stored = Config::load(&path, profile)?;
+    if !stored.needs_migration() {
+      return Ok(stored);
     }
-    .to_string();
+    let migrated = Signature::change_password(stored, daily_passphrase, daily_passphrase)?;
+    migrated.save(&path, profile)?;
+    Ok(migrated)
+  }
+
+  pub fn profiles(custom_config: Option<&str>) -> Result<profiles::Profiles> {
+    profiles::Profiles::load_or_default(&Self::config_path(custom_config))
+  }
+
+  pub fn config_needed(custom_config: Option<&str>, profile: Option<&str>) -> bool {
+    Self::profiles(custom_config).map_or(true, |p| !p.contains(profile))
+  }
+
+  pub fn config_path(custom_config: Option<&str>) -> String {
+    profiles::discover_config_path(custom_config)
+  }
+
+  /* Builds a client from an already loaded config, without touching the network. */
+  pub fn new(config: Config, daily_passphrase: &str) -> Result<Client> {
+    let api_url = config.api_url(None)?;
+    let transport = Box::new(transport::http_with(Default::default(), &config.network, &api_url)?);
+    let signature = Signature::load(config, daily_passphrase)?;
+    Ok(Client { signature, api_url, ledger: None, transport })
+  }
+
+  pub fn load(
+    custom_config: Option<&str>,
+    profile: Option<&str>,
+    api_url: Option<&str>,
+    daily_passphrase: &str,
+  ) -> Result<Client> {
+    Self::load_with(custom_config, profile, api_url, daily_passphrase, Default::default(), &Default::default())
+  }
+
+  /* Like load, with these timeouts and retries. Settings in `network` win over the profile's. */
+  pub fn load_with(
+    custom_config: Option<&str>,
+    profile: Option<&str>,
+    api_url: Option<&str>,
+    daily_passphrase: &str,
+    options: transport::TransportOptions,
+    network: &network::NetworkSettings,
+  ) -> Result<Client> {
+    let stored = Self::load_config(custom_config, profile, daily_passphrase)?;
+    let api_url = stored.api_url(api_url)?;
+    let transport = Box::new(transport::http_with(options, &stored.network.merge(network), &api_url)?);
     let signature = Signature::load(stored, daily_passphrase)?;
+    let ledger = Some(ledger::Ledger::for_config(&Self::config_path(custom_config), profile));
+    Ok(Client { signature, api_url, ledger, transport })
+  }
+
+  pub fn api_url(&self) -> &str {
+    &self.api_url
+  }
 
-    ureq::post(&format!("{}/signup", api_url))
-      .send_json(ureq::json!({
-        "signed_payload": signature.sign_message(b"Hello Constata.eu"),
-      }))
-      .unwrap()
+  /* Tells constata about our public key. Only needed once per key. */
+  pub fn register(&self) -> Result<()> {
+    self.transport
+      .post_json(&format!("{}/signup", self.api_url), &serde_json::json!({
+        "signed_payload": self.signature.sign_message(b"Hello Constata.eu"),
+      }))?
       .into_string()?;
+    Ok(())
+  }
 
-    Ok(Client { signature, api_url })
-  }
-
-  pub fn sign_and_timestamp(&self, bytes: &[u8], api_response: bool) -> Result<String> {
-    use ureq::Error::Status;
-
-    let response: DocumentBundle = match ureq::post(&format!("{}/documents/", self.api_url))
-      .send_json(ureq::json!({
-        "signed_payload": self.signature.sign_message(&bytes),
-      })) {
-        Ok(n) => n.into_json()?,
-        Err(Status(422, _)) => {
-          eprintln!("\n {} This document was already stamped\n", Emoji("🚨", "*"));
-          std::process::exit(1); // Exit with code 1 (fail)
-        },
-        Err(err) => return Err(Error::Network(Box::new(err))),
-      };
-    if api_response {
-      Ok(serde_json::to_string_pretty(&response)?)
-    } else {
-      println!("{} {}", style("Document state:").bold().bright(), response.state);
-      println!("{} {}", style("Document id:").bold().bright(), response.id);
-      match response.bulletin_id {
-        Some(bulletin_id) => println!("{} {}", style("Bulletin id:").bold().bright(), bulletin_id),
-        None => {}
-      }
-      println!("{} {}", style("Cost:").bold().bright(), response.cost);
-      println!("{} {}", style("Created At:").bold().bright(), response.created_at);
-      println!("{} {}", style("Buy token link:").bold().bright(), response.buy_tokens_link);
+  /* Records in the config file that the key in this profile was registered. */
+  pub fn mark_registered(custom_config: Option<&str>, profile: Option<&str>) -> Result<()> {
+    let path = Self::config_path(custom_config);
+    let mut config = Config::load(&path, profile)?;
+    config.mark_registered();
+    config.save(&path, profile)
+  }
+
+  pub fn sign_and_timestamp(&self, bytes: &[u8]) -> Result<DocumentBundle> {
+    self.submit(&self.sign(bytes))
+  }
+
+  /* Signs without touching the network, so the payload can be submitted later or elsewhere. */
+  pub fn sign(&self, bytes: &[u8]) -> signed_payload::SignedPayload {
+    self.signature.sign_message(bytes)
+  }
+
+  pub fn sign_path(&self, path: &str) -> Result<signed_payload::SignedPayload> {
+    Ok(self.sign(&read_document_file(path)?))
+  }
+
+  pub fn submit(&self, signed_payload: &signed_payload::SignedPayload) -> Result<DocumentBundle> {
+    Self::submit_to(self.transport(), &self.api_url, signed_payload)
+  }
 
-      Ok("".to_string())
+  /* Submitting needs no key, payloads signed on an air-gapped machine can be sent from any other. */
+  pub fn submit_to(
+    transport: &dyn transport::Transport,
+    api_url: &str,
+    signed_payload: &signed_payload::SignedPayload,
+  ) -> Result<DocumentBundle> {
+    let result = transport.post_json(&format!("{}/documents/", api_url), &serde_json::json!({
+      "signed_payload": signed_payload,
+    }));
+
+    match result {
+      Ok(response) => Ok(response.into_json()?),
+      Err(Error::Api { status: 422, body }) => Err(Error::already_stamped(&body)),
+      Err(err) => Err(err),
     }
-    
   }
 
   pub fn verify_website(&self, website: &[u8]) -> Result<(String, String)> {
-    let signed_payload = self.signature.sign_message(&website);
-    let response: serde_json::Value = ureq::post(&format!("{}/pubkey_domain_endorsements/", self.api_url))
-      .send_json(ureq::json!({
+    let signed_payload = self.signature.sign_message(website);
+    let response: serde_json::Value = self.transport
+      .post_json(&format!("{}/pubkey_domain_endorsements/", self.api_url), &serde_json::json!({
         "signed_payload": &signed_payload,
-      }))
-      .map_err(Box::new)?
+      }))?
       .into_json()?;
     Ok((serde_json::to_string_pretty(&response)?, signed_payload.signature.to_string()))
   }
 
-  pub fn website_verifications(&self, api_response: bool) -> Result<String> {
-    if api_response {
-      self.get_json("/pubkey_domain_endorsements")
-    } else {
-      let response: Vec<PubkeyDomainEndorsement> = serde_json::from_slice(self.get_json("/pubkey_domain_endorsements").unwrap().as_bytes())?;
-      for site in response {
-        println!("{} {}", style("Site:").bold().bright(), site.domain);
-        println!("{} {}", style("Verification state:").bold().bright(), site.state);
-        if site.state != "accepted" {
-          println!("{} {}\n", style("Attempts:").bold().bright(), site.attempts);
-        }
-      }
-      Ok("".to_string())
-    }
+  pub fn website_verifications(&self) -> Result<Vec<PubkeyDomainEndorsement>> {
+    self.get_response("/pubkey_domain_endorsements")?.into_json()
   }
 
-  pub fn get_response(&self, url: &str) -> Result<ureq::Response> {
-    let payload = ureq::json![{
-      "constata_eu_action": url,
-      "expires": chrono::offset::Utc::now() + chrono::Duration::hours(100),
-    }]
-    .to_string();
-
-    let auth_token = serde_json::to_string(&self.signature.sign_message(payload.as_bytes()))?;
-
-    ureq::get(&format!("{}{}", self.api_url, url))
-      .set("Authentication", &auth_token)
-      .call()
-      .map_err(|e| Box::new(e).into())
+  pub fn get_response(&self, url: &str) -> Result<transport::HttpResponse> {
+    let token = self.signature.auth_token(url)?;
+    self.transport.get(&format!("{}{}", self.api_url, url), &[("Authentication", &token)])
   }
 
   pub fn get(&self, url: &str) -> Result<String> {
-    Ok(self.get_response(url)?.into_string()?)
+    self.get_response(url)?.into_string()
   }
 
   pub fn get_json(&self, url: &str) -> Result<String> {
@@ -231,52 +486,19 @@ impl Client {
     Ok(serde_json::to_string_pretty(&response)?)
   }
 
-  pub fn sign_and_timestamp_path(&self, path: &str, api_response: bool) -> Result<String> {
-    let file_path = match std::fs::read(path) {
-      Ok(res) => res,
-      Err(ref e) if e.raw_os_error() == Some(21) => {
-        eprintln!("\n {} {} is a directory. Stamping could only be applied on files.\n   If you want to stamp an entire directory, consider compress it into a zip file\n", Emoji("🚨", "*"), style(path).bold().bright());
-        std::process::exit(1); // Exit with code 1 (fail)
-      },
-      Err(ref e) if e.raw_os_error() == Some(2) => {
-        eprintln!("\n {} File not found using path {}\n", Emoji("🚨", "*"), style(path).bold().bright());
-        std::process::exit(1); // Exit with code 1 (fail)
-      },
-      Err(err) => return Err(err.into()),
-    };
-    self.sign_and_timestamp(&file_path, api_response)
+  pub fn sign_and_timestamp_path(&self, path: &str) -> Result<DocumentBundle> {
+    let bytes = read_document_file(path)?;
+    let document = self.sign_and_timestamp(&bytes)?;
+    self.record_in_ledger(path, &signed_payload::hexdigest(&bytes), &document);
+    Ok(document)
   }
 
-  pub fn documents(&self,) -> Result<String> {
-    self.get_json("/documents")
+  pub fn documents(&self) -> Result<Vec<DocumentBundle>> {
+    self.get_response("/documents")?.into_json()
   }
 
-  pub fn list_documents(&self) -> Result<String> {
-    let documents: Vec<DocumentBundle> = serde_json::from_slice(self.get_json("/documents").unwrap().as_bytes())?;
-    println!("{} {} {}", Emoji("📑", "*"), style("Total Documents:").bold().bright(), documents.len());
-    println!("{}", style("Document ID / Bulletin ID:").bold().bright());
-    for document in documents {
-      println!("  {} / {}", document.id, document.bulletin_id.unwrap() )
-    }
-    Ok("".to_string())
-  }
-
-  pub fn document(&self, document_id: &str, api_response: bool) -> Result<String> {
-    let response: DocumentBundle = self.get_response(&format!("/documents/{}", document_id))?.into_json()?;
-    if api_response {
-      Ok(serde_json::to_string_pretty(&response)?)
-    } else {
-      let bulletin_id = response.bulletin_id.unwrap();
-      println!("{} {}", style("Document state:").bold().bright(), response.state);
-      println!("{} {}", style("Document id:").bold().bright(), response.id);
-      println!("{} {}", style("Bulletin id:").bold().bright(), bulletin_id);
-      if bulletin_id.is_i64() {
-        println!("{} {}", style("Bulletin state:").bold().bright(), response.bulletins[&bulletin_id.as_i64().expect("must be a number")].state);
-      }
-      println!("{} {}", style("Cost:").bold().bright(), response.cost);
-      println!("{} {}", style("Created At:").bold().bright(), response.created_at);
-      Ok("".to_string())
-    }
+  pub fn document(&self, document_id: &str) -> Result<DocumentBundle> {
+    self.get_response(&format!("/documents/{}", document_id))?.into_json()
   }
 
   pub fn fetch_proof(&self, document_id: &str) -> Result<String> {
@@ -284,27 +506,131 @@ impl Client {
   }
 
   pub fn fetch_each_proof(&self, document_id: &str) -> Result<Vec<u8>> {
-    use std::io::Read;
-    let response =
-      self.get_response(&format!("/documents/{}/each_part_html_proof", document_id))?;
-    let mut bytes: Vec<u8> = vec![];
-    response
-      .into_reader()
-      .take(10_000_000)
-      .read_to_end(&mut bytes)?;
+    self.fetch_each_proof_with(document_id, |_, _| ())
+  }
+
+  /* `on_progress` gets the bytes downloaded so far, and the total if the API sent it.
+   * Fails instead of returning a partial ZIP if the download is cut short.
+   */
+  pub fn fetch_each_proof_with(&self, document_id: &str, on_progress: impl FnMut(u64, Option<u64>)) -> Result<Vec<u8>> {
+    let bytes = self
+      .get_response(&format!("/documents/{}/each_part_html_proof", document_id))?
+      .into_bytes_with(on_progress)?;
+    proofs::check_zip(&bytes)?;
     Ok(bytes)
   }
 
-  pub fn account_state(&self) -> Result<String> {
-    let response: AccountState = self.get_response("/account_state")?.into_json()?;
-    Ok(serde_json::to_string_pretty(&response)?)
+  /* Downloads the per part proofs ZIP into `path`, through its ".part" file, returning its bytes.
+   * When a download is cut short the ".part" file is kept, and the next call only asks the API
+   * for the missing bytes with a Range header. If the API sends the whole ZIP instead, it starts over.
+   * The ZIP is checked before it's moved into place, a ".part" file that doesn't make a valid ZIP is removed.
+   */
+  pub fn download_each_proof_with(
+    &self,
+    document_id: &str,
+    path: &std::path::Path,
+    mut on_progress: impl FnMut(u64, Option<u64>),
+  ) -> Result<Vec<u8>> {
+    let url = format!("/documents/{}/each_part_html_proof", document_id);
+    let partial = proofs::partial_path(path);
+    let resume_from = std::fs::metadata(&partial).map_or(0, |m| m.len());
+
+    let token = self.signature.auth_token(&url)?;
+    let range = format!("bytes={}-", resume_from);
+    let mut headers = vec![("Authentication", token.as_str())];
+    if resume_from > 0 {
+      headers.push(("Range", range.as_str()));
+    }
+
+    let response = match self.transport.get(&format!("{}{}", self.api_url, url), &headers) {
+      Err(Error::Api { status: 416, .. }) if resume_from > 0 => {
+        std::fs::remove_file(&partial)?;
+        return self.download_each_proof_with(document_id, path, on_progress);
+      },
+      result => result?,
+    };
+
+    let (mut file, offset) = if response.status == 206 {
+      (std::fs::OpenOptions::new().append(true).open(&partial)?, resume_from)
+    } else {
+      (std::fs::File::create(&partial)?, 0)
+    };
+    response.write_to_with(&mut file, |done, total| on_progress(offset + done, total.map(|t| offset + t)))?;
+    drop(file);
+
+    let bytes = std::fs::read(&partial)?;
+    if let Err(err) = proofs::check_zip(&bytes) {
+      std::fs::remove_file(&partial)?;
+      return Err(err);
+    }
+    std::fs::rename(&partial, path)?;
+    Ok(bytes)
+  }
+
+  pub fn account_state(&self) -> Result<AccountState> {
+    self.get_response("/account_state")?.into_json()
   }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
-  use mockito;
+
+  #[test]
+  fn resolves_api_urls() {
+    let (mut config, _mnemonic) = Signature::create("staging", "very_secret", "not_so_secret").unwrap();
+    assert_eq!(config.api_url(None).unwrap(), "https://api-staging.constata.eu");
+    assert_eq!(config.api_url(Some("http://mock:1234/")).unwrap(), "http://mock:1234");
+    assert!(matches!(config.api_url(Some("mock:1234")), Err(Error::InvalidApiUrl(_))));
+
+    config.environment = "mirror".to_string();
+    assert!(matches!(config.api_url(None), Err(Error::UnknownEnvironment(_))));
+
+    config.set_api_url(Some("https://constata.mirror.example")).unwrap();
+    assert_eq!(config.api_url(None).unwrap(), "https://constata.mirror.example");
+  }
+
+  #[test]
+  fn builds_offline_and_registers_explicitly() {
+    let (mut config, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    config.set_api_url(Some(&mockito::server_url())).unwrap();
+    assert!(!config.is_registered());
+
+    let mock = mockito::mock("POST", "/signup")
+        .with_status(200)
+        .with_body("{}")
+        .expect(1)
+        .create();
+
+    let client = Client::new(config.clone(), "not_so_secret").unwrap();
+    assert!(matches!(Client::new(config, "wrong"), Err(Error::WrongPassword)));
+    client.register().unwrap();
+
+    mock.assert();
+  }
+
+  #[test]
+  fn signs_offline_and_submits_later() {
+    let (config, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    let signature = Signature::load(config, "not_so_secret").unwrap();
+    let client = Client { signature, api_url: "http://offline.invalid".to_string(), ledger: None, transport: transport::default_transport() };
+
+    let signed = client.sign(b"air gapped hello");
+    assert!(signed.signed_ok().unwrap());
+    let stored: signed_payload::SignedPayload = serde_json::from_str(&serde_json::to_string(&signed).unwrap()).unwrap();
+
+    let mock = mockito::mock("POST", "/documents/")
+        .match_body(mockito::Matcher::PartialJson(serde_json::json!({"signed_payload": &stored})))
+        .with_status(200)
+        .with_body(r#"{"state":"Parked","id":"1-2","person_id":1,"bulletin_id":null,"parts":[],"created_at":"2022-01-05T08:04:47.166681Z","cost":"1","gift_id":null,"bulletins":{},"buy_tokens_link":null}"#)
+        .expect(1)
+        .create();
+
+    let document = Client::submit_to(transport::default_transport().as_ref(), &mockito::server_url(), &stored).unwrap();
+    assert_eq!(document.id, "1-2");
+
+    mock.assert();
+  }
 
   #[test]
   fn account_state_response() {
@@ -312,7 +638,7 @@ mod tests {
     let signature = Signature::load(config, "not_so_secret").unwrap();
 
     let api_url = mockito::server_url();
-    let client = Client { signature, api_url };
+    let client = Client { signature, api_url, ledger: None, transport: transport::default_transport() };
     let mock = mockito::mock("GET", "/account_state")
         .with_status(200)
         .with_header("content-type", "application/json")
@@ -320,17 +646,17 @@ mod tests {
         .expect(1)
         .create();
 
-    let json_response = client.account_state().unwrap();
+    let account_state = client.account_state().unwrap();
 
     assert_eq!(
-      json_response,
-r#"{
-  "missing": "1",
-  "parked_count": 1,
-  "person_id": 19,
-  "token_balance": "0",
-  "total_document_count": 367
-}"#.to_string()
+      account_state,
+      AccountState {
+        missing: rust_decimal::Decimal::from(1),
+        parked_count: 1,
+        person_id: 19,
+        token_balance: rust_decimal::Decimal::from(0),
+        total_document_count: 367,
+      }
     );
 
     mock.assert();
@@ -342,7 +668,7 @@ r#"{
     let signature = Signature::load(config, "not_so_secret").unwrap();
 
     let api_url = mockito::server_url();
-    let client = Client { signature, api_url };
+    let client = Client { signature, api_url, ledger: None, transport: transport::default_transport() };
     let mock = mockito::mock("GET", "/documents/1")
         .with_status(200)
         .with_header("content-type", "application/json")
@@ -350,139 +676,140 @@ r#"{
         .expect(1)
         .create();
 
-    let json_response = client.document(&"1".to_string(), true).unwrap();
+    let document = client.document("1").unwrap();
 
+    assert_eq!(document.id, "1-1");
+    assert_eq!(document.state, DocumentState::Parked);
+    assert_eq!(document.bulletin_id, Some(303));
+    assert!(document.bulletin().is_none());
+    assert_eq!(document.cost, rust_decimal::Decimal::from(1));
+    assert_eq!(document.created_at.to_rfc3339(), "2022-01-05T08:04:47.166681+00:00");
+    assert_eq!(document.gift_id, None);
     assert_eq!(
-      json_response,
-r#"{
-  "bulletin_id": 303,
-  "bulletins": {},
-  "cost": "1",
-  "created_at": "2022-01-05T08:04:47.166681Z",
-  "gift_id": null,
-  "id": "1-1",
-  "parts": [
-    {
-      "content_type": "multipart/alternative",
-      "document_id": "1-9",
-      "friendly_name": "doc",
-      "hash": "9",
-      "id": "bc",
-      "is_base": true,
-      "signatures": [
-        {
-          "document_part_id": "bc",
-          "endorsements": [],
-          "id": 4,
-          "pubkey_id": "mw",
-          "signature": "HN",
-          "signature_hash": "be"
-        }
-      ],
-      "size_in_bytes": 1410
-    },
-    {
-      "content_type": "text/plain",
-      "document_id": "1-9",
-      "friendly_name": "hello.txt",
-      "hash": "68",
-      "id": "f1",
-      "is_base": false,
-      "signatures": [],
-      "size_in_bytes": 59
-    },
-    {
-      "content_type": "text/html",
-      "document_id": "1-9",
-      "friendly_name": "unnamed_attachment.txt",
-      "hash": "6f",
-      "id": "9f",
-      "is_base": false,
-      "signatures": [],
-      "size_in_bytes": 94
-    },
-    {
-      "content_type": "application/zip",
-      "document_id": "1-9",
-      "friendly_name": "unnamed_attachment.zip",
-      "hash": "83",
-      "id": "b6",
-      "is_base": false,
-      "signatures": [],
-      "size_in_bytes": 530
-    },
-    {
-      "content_type": "text/plain",
-      "document_id": "1-95",
-      "friendly_name": "bar/baz.txt",
-      "hash": "bf0",
-      "id": "c7",
-      "is_base": false,
-      "signatures": [],
-      "size_in_bytes": 4
-    },
-    {
-      "content_type": "text/plain",
-      "document_id": "1-99",
-      "friendly_name": "foo.txt",
-      "hash": "b5",
-      "id": "59",
-      "is_base": false,
-      "signatures": [],
-      "size_in_bytes": 4
-    }
-  ],
-  "person_id": 1,
-  "state": "Parked",
-  "buy_tokens_link": "https://localhost:8000/invoices/#link_token=boss+almighty+registrar+ashes+unsalted&minimum_suggested=4"
-}"#.to_string()
+      document.buy_tokens_link.as_deref(),
+      Some("https://localhost:8000/invoices/#link_token=boss+almighty+registrar+ashes+unsalted&minimum_suggested=4")
     );
+    assert_eq!(document.parts.len(), 6);
+    assert!(document.parts[0].is_base);
+    assert_eq!(document.parts[0].size_in_bytes, 1410);
+    assert_eq!(document.parts[0].signatures[0].id, 4);
+    assert_eq!(document.parts[0].signatures[0].pubkey_id, "mw");
+    assert!(document.parts[0].signatures[0].endorsements.is_empty());
+    assert_eq!(document.parts[4].friendly_name, "bar/baz.txt");
 
     mock.assert();
   }
   
+  #[test]
+  fn already_stamped_is_an_error() {
+    let (config, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    let signature = Signature::load(config, "not_so_secret").unwrap();
+
+    let api_url = mockito::server_url();
+    let client = Client { signature, api_url, ledger: None, transport: transport::default_transport() };
+    let mock = mockito::mock("POST", "/documents/")
+        .with_status(422)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"error": "already_stamped", "document_id": "1-9"}"#)
+        .expect(1)
+        .create();
+
+    match client.sign_and_timestamp(b"hello world") {
+      Err(Error::AlreadyStamped { document_id }) => assert_eq!(document_id.as_deref(), Some("1-9")),
+      other => panic!("Expected AlreadyStamped, got {:?}", other.map(|d| d.id)),
+    }
+
+    mock.assert();
+  }
+
+  #[test]
+  fn stamping_missing_paths_is_an_error() {
+    let (config, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    let signature = Signature::load(config, "not_so_secret").unwrap();
+    let client = Client { signature, api_url: mockito::server_url(), ledger: None, transport: transport::default_transport() };
+
+    assert!(matches!(client.sign_and_timestamp_path("src"), Err(Error::IsADirectory(_))));
+    assert!(matches!(client.sign_and_timestamp_path("does_not_exist.txt"), Err(Error::FileNotFound(_))));
+  }
+
   #[test]
   fn website_verifications_response() {
     let (config, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
     let signature = Signature::load(config, "not_so_secret").unwrap();
 
     let api_url = mockito::server_url();
-    let client = Client { signature, api_url };
+    let client = Client { signature, api_url, ledger: None, transport: transport::default_transport() };
     let mock = mockito::mock("GET", "/pubkey_domain_endorsements")
         .with_status(200)
         .with_header("content-type", "application/json")
         .with_body(r#"[{"attempts": 1,"attempts_log": "https://pepe.com/constata_eu_domain_verification.txt: Network Error: timed out reading response\n","bulletin_id": 268,"domain": "https://pepe.com","evidence": null,"evidence_hash": null,"id": 5,"next_attempt": "2022-02-26T11:43:56.024651Z","pubkey_id": "1FhwxxbDsxpA6xmje4LQCKwd5XRBdy8VCa","request_signature": "IBlq311o1WTxLNTrwU4zetJn1hvhTALbXOIIH60Nz6gFcNkBidHBo3UZSlV730w/7kCJUWg8fg6XVyyGnPM1vzQ=","state": "pending"}]"#)
-        .expect(2)
+        .expect(1)
         .create();
 
-    let json_response = client.website_verifications(true).unwrap();
+    let sites = client.website_verifications().unwrap();
 
-    assert_eq!(
-      json_response,
-r#"[
-  {
-    "attempts": 1,
-    "attempts_log": "https://pepe.com/constata_eu_domain_verification.txt: Network Error: timed out reading response\n",
-    "bulletin_id": 268,
-    "domain": "https://pepe.com",
-    "evidence": null,
-    "evidence_hash": null,
-    "id": 5,
-    "next_attempt": "2022-02-26T11:43:56.024651Z",
-    "pubkey_id": "1FhwxxbDsxpA6xmje4LQCKwd5XRBdy8VCa",
-    "request_signature": "IBlq311o1WTxLNTrwU4zetJn1hvhTALbXOIIH60Nz6gFcNkBidHBo3UZSlV730w/7kCJUWg8fg6XVyyGnPM1vzQ=",
-    "state": "pending"
-  }
-]"#.to_string()
-    );
+    assert_eq!(sites.len(), 1);
+    assert_eq!(sites[0].id, 5);
+    assert_eq!(sites[0].domain, "https://pepe.com");
+    assert_eq!(sites[0].bulletin_id, Some(268));
+    assert_eq!(sites[0].state, PubkeyDomainEndorsementState::Pending);
+    assert_eq!(sites[0].attempts, 1);
+    assert_eq!(sites[0].evidence, None);
+    assert_eq!(sites[0].next_attempt.to_rfc3339(), "2022-02-26T11:43:56.024651+00:00");
 
-    let response = client.website_verifications(false).unwrap();
+    mock.assert();
+  }
 
-    assert_eq!(
-      response,
-      ""
-    );
+  #[test]
+  fn resumes_each_proof_downloads_from_their_part_file() {
+    use std::io::Write;
+    let mut writer = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+    writer.start_file("contract.pdf.html", zip::write::FileOptions::default()).unwrap();
+    writer.write_all(b"<html>contract</html>").unwrap();
+    let zip = writer.finish().unwrap().into_inner();
 
-    mock.assert();
+    let (config, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    let signature = Signature::load(config, "not_so_secret").unwrap();
+    let client = Client { signature, api_url: mockito::server_url(), ledger: None, transport: transport::default_transport() };
+    let path = std::env::temp_dir().join(format!("constata_resumed_{}.zip", std::process::id()));
+    let partial = proofs::partial_path(&path);
+
+    std::fs::write(&partial, &zip[..20]).unwrap();
+    let resumed = mockito::mock("GET", "/documents/1-resumed/each_part_html_proof")
+        .match_header("Range", "bytes=20-")
+        .with_status(206)
+        .with_body(&zip[20..])
+        .expect(1)
+        .create();
+    let mut progress = vec![];
+    let bytes = client.download_each_proof_with("1-resumed", &path, |done, total| progress.push((done, total))).unwrap();
+    assert_eq!(bytes, zip);
+    assert_eq!(std::fs::read(&path).unwrap(), zip);
+    assert!(!partial.exists());
+    assert_eq!(progress.last(), Some(&(zip.len() as u64, Some(zip.len() as u64))));
+    resumed.assert();
+
+    std::fs::write(&partial, b"not the same zip").unwrap();
+    let whole = mockito::mock("GET", "/documents/1-whole/each_part_html_proof")
+        .match_header("Range", "bytes=16-")
+        .with_status(200)
+        .with_body(&zip)
+        .expect(1)
+        .create();
+    assert_eq!(client.download_each_proof_with("1-whole", &path, |_, _| ()).unwrap(), zip);
+    whole.assert();
+
+    std::fs::write(&partial, b"not the same zip").unwrap();
+    let mismatched = mockito::mock("GET", "/documents/1-mismatched/each_part_html_proof")
+        .match_header("Range", "bytes=16-")
+        .with_status(206)
+        .with_body(&zip[16..])
+        .expect(1)
+        .create();
+    assert!(matches!(client.download_each_proof_with("1-mismatched", &path, |_, _| ()), Err(Error::Zip(_))));
+    assert!(!partial.exists());
+    mismatched.assert();
+
+    std::fs::remove_file(path).unwrap();
   }
-}
\ No newline at end of file
+}
@@ -0,0 +1,128 @@
+use chrono::{DateTime, Utc};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/* These are the shapes returned by Constata's public API.
+ * Unknown states deserialize to an `Unknown` variant so that a newer
+ * server does not break older clients.
+ */
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountState {
+  pub missing: Decimal,
+  pub parked_count: i64,
+  pub person_id: i32,
+  pub token_balance: Decimal,
+  pub total_document_count: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BulletinState {
+  Draft,
+  Proposed,
+  Submitted,
+  Published,
+  #[serde(other)]
+  Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bulletin {
+  pub id: i32,
+  pub state: BulletinState,
+  pub started_at: DateTime<Utc>,
+  pub hash: Option<String>,
+  pub transaction: Option<String>,
+  pub transaction_hash: Option<String>,
+  pub block_hash: Option<String>,
+  pub block_time: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DocumentState {
+  Parked,
+  Accepted,
+  Published,
+  #[serde(other)]
+  Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DocumentBundle {
+  pub bulletin_id: Option<i32>,
+  pub bulletins: HashMap<i32, Bulletin>,
+  pub cost: Decimal,
+  pub created_at: DateTime<Utc>,
+  pub gift_id: Option<i32>,
+  pub id: String,
+  pub parts: Vec<DocumentPart>,
+  pub person_id: i32,
+  pub state: DocumentState,
+  pub buy_tokens_link: Option<String>,
+}
+
+impl DocumentBundle {
+  pub fn bulletin(&self) -> Option<&Bulletin> {
+    self.bulletin_id.and_then(|id| self.bulletins.get(&id))
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DocumentPart {
+  pub id: String,
+  pub document_id: String,
+  pub friendly_name: String,
+  pub hash: String,
+  pub content_type: String,
+  pub size_in_bytes: i64,
+  pub signatures: Vec<PartSignature>,
+  pub is_base: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PartSignature {
+  pub id: i32,
+  pub document_part_id: String,
+  pub pubkey_id: String,
+  pub signature: String,
+  pub signature_hash: String,
+  pub endorsements: Vec<Endorsement>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Endorsement {
+  Website { url: String },
+  EmailAddress { address: String, keep_private: bool },
+  Telegram {
+    id: i64,
+    username: Option<String>,
+    first_name: Option<String>,
+    last_name: Option<String>,
+  },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PubkeyDomainEndorsementState {
+  Pending,
+  Accepted,
+  Failed,
+  #[serde(other)]
+  Unknown,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PubkeyDomainEndorsement {
+  pub attempts: i32,
+  pub attempts_log: String,
+  pub bulletin_id: Option<i32>,
+  pub domain: String,
+  pub evidence: Option<String>,
+  pub evidence_hash: Option<String>,
+  pub id: i32,
+  pub next_attempt: DateTime<Utc>,
+  pub pubkey_id: String,
+  pub request_signature: String,
+  pub state: PubkeyDomainEndorsementState,
+}
@@ -0,0 +1,297 @@
+/* Prints command results in the format chosen with --format.
+ * Structured formats wrap results in a versioned envelope:
+ *   {"schema_version": 1, "command": "list", "data": [...]}
+ * schema/output-v1.json describes the data for every command. Within a schema
+ * version fields may be added, but never renamed, retyped or removed.
+ */
+use constata_client_lib::{profiles::Profiles, signed_payload::hexdigest, verify::Verification, DocumentBundle};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::path::{Path, PathBuf};
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+pub const FORMATS: &[&str] = &["human", "json", "ndjson", "csv", "yaml"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+  Human,
+  Json,
+  Ndjson,
+  Csv,
+  Yaml,
+}
+
+impl std::str::FromStr for Format {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Format, String> {
+    match s {
+      "human" => Ok(Format::Human),
+      "json" => Ok(Format::Json),
+      "ndjson" => Ok(Format::Ndjson),
+      "csv" => Ok(Format::Csv),
+      "yaml" => Ok(Format::Yaml),
+      _ => Err(format!("Unknown output format {}, use one of {}", s, FORMATS.join(", "))),
+    }
+  }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+  schema_version: u32,
+  command: &'a str,
+  data: Value,
+}
+
+pub struct Output {
+  pub format: Format,
+  command: String,
+}
+
+impl Output {
+  /* `command` is the subcommand path, like "profile list". */
+  pub fn new(format: Format, command: &str) -> Output {
+    Output { format, command: command.to_string() }
+  }
+
+  pub fn is_human(&self) -> bool {
+    self.format == Format::Human
+  }
+
+  /* Prints a command result. `human` renders it for the human format. */
+  pub fn print<T: Serialize>(&self, data: &T, human: impl FnOnce(&T) -> String) {
+    match self.format {
+      Format::Human => println!("{}", human(data)),
+      _ => print!("{}", self.render(data)),
+    }
+  }
+
+  /* Prints prose for whoever is at the terminal, like the explanations of interactive prompts.
+   * Structured formats keep stdout for results, so it goes to stderr there.
+   */
+  pub fn say(&self, text: &str) {
+    if self.is_human() {
+      println!("{}", text);
+    } else {
+      eprintln!("{}", text);
+    }
+  }
+
+  /* Prints progress while a command runs. Only human and ndjson output show it,
+   * the other formats are a single document printed when the command is done.
+   */
+  pub fn event<T: Serialize>(&self, data: &T, human: impl FnOnce(&T) -> String) {
+    match self.format {
+      Format::Human => println!("{}", human(data)),
+      Format::Ndjson => print!("{}", self.render(data)),
+      _ => {},
+    }
+  }
+
+  pub fn render<T: Serialize>(&self, data: &T) -> String {
+    let data = serde_json::to_value(data).expect("Output data to serialize as JSON");
+    let envelope = |data: Value| Envelope { schema_version: SCHEMA_VERSION, command: &self.command, data };
+
+    match self.format {
+      Format::Human | Format::Json => format!("{}\n", serde_json::to_string_pretty(&envelope(data)).unwrap()),
+      Format::Yaml => serde_yaml::to_string(&envelope(data)).expect("Output data to serialize as YAML"),
+      Format::Ndjson => rows(data)
+        .into_iter()
+        .map(|row| format!("{}\n", serde_json::to_string(&envelope(row)).unwrap()))
+        .collect(),
+      Format::Csv => csv(rows(data)),
+    }
+  }
+}
+
+/* Lists are one record per item, anything else is a single record. */
+fn rows(data: Value) -> Vec<Value> {
+  match data {
+    Value::Array(items) => items,
+    other => vec![other],
+  }
+}
+
+/* CSV has no envelope. Columns are the record fields, nested objects and lists
+ * are written as JSON in their cell.
+ */
+fn csv(records: Vec<Value>) -> String {
+  let flat: Vec<Map<String, Value>> = records
+    .into_iter()
+    .map(|record| match record {
+      Value::Object(fields) => fields,
+      other => std::iter::once(("value".to_string(), other)).collect(),
+    })
+    .collect();
+
+  let mut columns: Vec<&String> = vec![];
+  for row in &flat {
+    for key in row.keys() {
+      if !columns.contains(&key) {
+        columns.push(key);
+      }
+    }
+  }
+
+  let mut lines = vec![columns.iter().map(|c| csv_field(c)).collect::<Vec<_>>().join(",")];
+  for row in &flat {
+    let cells = columns.iter().map(|column| match row.get(*column) {
+      None | Some(Value::Null) => String::new(),
+      Some(Value::String(s)) => csv_field(s),
+      Some(other) => csv_field(&other.to_string()),
+    });
+    lines.push(cells.collect::<Vec<_>>().join(","));
+  }
+  lines.into_iter().map(|line| format!("{}\n", line)).collect()
+}
+
+fn csv_field(value: &str) -> String {
+  if value.contains(&[',', '"', '\n', '\r'][..]) {
+    format!("\"{}\"", value.replace('"', "\"\""))
+  } else {
+    value.to_string()
+  }
+}
+
+/* Results of commands that have no library type of their own. */
+
+/* A downloaded proof. It's either saved to `path`, extracted into `files`,
+ * or printed as `content` when the output file is "-".
+ */
+#[derive(Debug, Serialize)]
+pub struct Proof {
+  pub document_id: String,
+  pub media_type: &'static str,
+  pub size: usize,
+  pub sha256: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub path: Option<String>,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub files: Vec<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub encoding: Option<&'static str>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub content: Option<String>,
+}
+
+impl Proof {
+  pub fn new(document_id: &str, media_type: &'static str, bytes: &[u8]) -> Proof {
+    Proof {
+      document_id: document_id.to_string(),
+      media_type,
+      size: bytes.len(),
+      sha256: hexdigest(bytes),
+      path: None,
+      files: vec![],
+      encoding: None,
+      content: None,
+    }
+  }
+
+  pub fn saved_to(mut self, path: &Path, files: &[PathBuf]) -> Proof {
+    self.path = Some(path.display().to_string());
+    self.files = files.iter().map(|f| f.display().to_string()).collect();
+    self
+  }
+
+  /* HTML goes as is, anything else base64 encoded. */
+  pub fn with_content(mut self, bytes: &[u8]) -> Proof {
+    match std::str::from_utf8(bytes) {
+      Ok(text) if self.media_type == "text/html" => {
+        self.encoding = Some("utf-8");
+        self.content = Some(text.to_string());
+      },
+      _ => {
+        self.encoding = Some("base64");
+        self.content = Some(base64::encode(bytes));
+      },
+    }
+    self
+  }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProofCheck {
+  pub path: String,
+  pub referenced: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VerifyResult {
+  pub file: String,
+  pub ok: bool,
+  pub signature: Option<Verification>,
+  pub proof: Option<ProofCheck>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Submitted {
+  pub path: String,
+  pub document: Option<DocumentBundle>,
+  pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Waited {
+  pub document: DocumentBundle,
+  pub proof_path: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProfileSummary {
+  pub name: String,
+  pub default: bool,
+  pub environment: String,
+  pub public_key: String,
+}
+
+impl ProfileSummary {
+  pub fn all(profiles: &Profiles) -> Vec<ProfileSummary> {
+    let default = profiles.name(None);
+    profiles
+      .profiles
+      .iter()
+      .map(|(name, config)| ProfileSummary {
+        name: name.clone(),
+        default: name == default,
+        environment: config.environment().to_string(),
+        public_key: config.public_key().to_string(),
+      })
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn renders_every_format_with_the_same_data() {
+    let data = json!([
+      { "id": "1-a", "state": "Parked", "bulletin": { "id": 3 }, "parts": ["x"] },
+      { "id": "1-b", "state": "Published, finally", "bulletin": null, "parts": [] },
+    ]);
+
+    let json = Output::new(Format::Json, "list").render(&data);
+    let parsed: Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed["schema_version"], SCHEMA_VERSION);
+    assert_eq!(parsed["command"], "list");
+    assert_eq!(parsed["data"], data);
+
+    let ndjson = Output::new(Format::Ndjson, "list").render(&data);
+    let lines: Vec<Value> = ndjson.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[1]["data"]["id"], "1-b");
+    assert_eq!(lines[1]["schema_version"], SCHEMA_VERSION);
+
+    assert_eq!(
+      Output::new(Format::Csv, "list").render(&data),
+      "bulletin,id,parts,state\n\"{\"\"id\"\":3}\",1-a,\"[\"\"x\"\"]\",Parked\n,1-b,[],\"Published, finally\"\n",
+    );
+
+    let yaml: Value = serde_yaml::from_str(&Output::new(Format::Yaml, "list").render(&data)).unwrap();
+    assert_eq!(yaml["data"], data);
+  }
+}
@@ -0,0 +1,316 @@
+use super::*;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::PathBuf;
+
+/* Places the daily password can come from, other than an interactive prompt.
+ * Command line arguments show up in `ps` and shell history, so the CLI
+ * prefers any of the others when available.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum PasswordSource {
+  Literal(String),
+  Env(String),
+  File(PathBuf),
+  Stdin,
+  SecretStore(String),
+}
+
+impl PasswordSource {
+  pub fn read(&self) -> Result<Option<String>> {
+    match self {
+      PasswordSource::Literal(password) => Ok(Some(password.clone())),
+      PasswordSource::Env(var) => Ok(std::env::var(var).ok().filter(|p| !p.is_empty())),
+      PasswordSource::File(path) => Ok(Some(first_line(&std::fs::read_to_string(path)?))),
+      PasswordSource::Stdin => {
+        let mut input = String::new();
+        std::io::stdin().read_to_string(&mut input)?;
+        Ok(Some(first_line(&input)))
+      },
+      PasswordSource::SecretStore(account) => match default_secret_store() {
+        Some(store) => store.get(account),
+        None => Ok(None),
+      },
+    }
+  }
+}
+
+fn first_line(contents: &str) -> String {
+  contents.lines().next().unwrap_or("").to_string()
+}
+
+/* The password from the first source that has one. */
+pub fn resolve(sources: &[PasswordSource]) -> Result<Option<String>> {
+  for source in sources {
+    if let Some(password) = source.read()? {
+      return Ok(Some(password));
+    }
+  }
+  Ok(None)
+}
+
+/* Secrets are stored per config file and profile. The profile is resolved
+ * like Profiles::name does, so leaving out --profile finds the default profile's secret.
+ */
+pub fn account_for(config_path: &str, profile: Option<&str>) -> String {
+  let path = std::fs::canonicalize(config_path)
+    .map(|p| p.to_string_lossy().to_string())
+    .unwrap_or_else(|_| config_path.to_string());
+  let profiles = profiles::Profiles::load_or_default(config_path).unwrap_or_default();
+  format!("{}#{}", path, profiles.name(profile))
+}
+
+pub trait SecretStore {
+  fn get(&self, account: &str) -> Result<Option<String>>;
+  fn set(&self, account: &str, secret: &str) -> Result<()>;
+  fn delete(&self, account: &str) -> Result<()>;
+
+  /* The file holding the secret for `account` unencrypted, if it's kept in one. */
+  fn plaintext_path(&self, _account: &str) -> Option<PathBuf> {
+    None
+  }
+}
+
+/* Keeps secrets unencrypted in a JSON file only readable by its owner.
+ * Anyone who can read it can decrypt your key, so it's only used when
+ * CONSTATA_SECRETS_FILE names it, for places with no OS keyring like headless Linux boxes.
+ */
+pub struct FileSecretStore {
+  path: PathBuf,
+}
+
+impl FileSecretStore {
+  pub fn new<P: Into<PathBuf>>(path: P) -> FileSecretStore {
+    FileSecretStore { path: path.into() }
+  }
+
+  /* The file named by CONSTATA_SECRETS_FILE, if it's set. */
+  pub fn from_env() -> Option<FileSecretStore> {
+    std::env::var_os("CONSTATA_SECRETS_FILE").filter(|p| !p.is_empty()).map(FileSecretStore::new)
+  }
+
+  fn read_all(&self) -> Result<BTreeMap<String, String>> {
+    match std::fs::read_to_string(&self.path) {
+      Ok(contents) => Ok(serde_json::from_str(&contents)?),
+      Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  fn write_all(&self, secrets: &BTreeMap<String, String>) -> Result<()> {
+    if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+      std::fs::create_dir_all(parent)?;
+    }
+    write_private_file(&self.path, serde_json::to_string_pretty(secrets)?.as_bytes())
+  }
+}
+
+impl SecretStore for FileSecretStore {
+  fn get(&self, account: &str) -> Result<Option<String>> {
+    Ok(self.read_all()?.remove(account))
+  }
+
+  fn plaintext_path(&self, account: &str) -> Option<PathBuf> {
+    self.read_all().ok()?.contains_key(account).then(|| self.path.clone())
+  }
+
+  fn set(&self, account: &str, secret: &str) -> Result<()> {
+    let mut secrets = self.read_all()?;
+    secrets.insert(account.to_string(), secret.to_string());
+    self.write_all(&secrets)
+  }
+
+  fn delete(&self, account: &str) -> Result<()> {
+    let mut secrets = self.read_all()?;
+    if secrets.remove(account).is_some() {
+      self.write_all(&secrets)?;
+    }
+    Ok(())
+  }
+}
+
+/* Uses the OS keyring. When there's no keyring service, like on headless Linux,
+ * it falls back to the `fallback` file store if there is one, and fails otherwise.
+ */
+#[cfg(feature = "keyring")]
+pub struct KeyringSecretStore {
+  service: String,
+  fallback: Option<FileSecretStore>,
+}
+
+#[cfg(feature = "keyring")]
+impl KeyringSecretStore {
+  pub fn new(service: &str, fallback: Option<FileSecretStore>) -> KeyringSecretStore {
+    KeyringSecretStore { service: service.to_string(), fallback }
+  }
+
+  fn entry(&self, account: &str) -> keyring::Result<keyring::Entry> {
+    keyring::Entry::new(&self.service, account)
+  }
+
+  fn fallback(&self, unavailable: keyring::Error) -> Result<&FileSecretStore> {
+    self.fallback.as_ref().ok_or_else(|| Error::Keyring(unavailable.to_string()))
+  }
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_unavailable(err: &keyring::Error) -> bool {
+  matches!(err, keyring::Error::NoStorageAccess(_) | keyring::Error::PlatformFailure(_))
+}
+
+#[cfg(feature = "keyring")]
+impl SecretStore for KeyringSecretStore {
+  /* Without a keyring service or fallback nothing could have been stored, so there's no secret. */
+  fn get(&self, account: &str) -> Result<Option<String>> {
+    match self.entry(account).and_then(|entry| entry.get_password()) {
+      Ok(secret) => Ok(Some(secret)),
+      Err(keyring::Error::NoEntry) => Ok(None),
+      Err(e) if keyring_unavailable(&e) => match &self.fallback {
+        Some(file) => file.get(account),
+        None => Ok(None),
+      },
+      Err(e) => Err(Error::Keyring(e.to_string())),
+    }
+  }
+
+  fn set(&self, account: &str, secret: &str) -> Result<()> {
+    match self.entry(account).and_then(|entry| entry.set_password(secret)) {
+      Ok(()) => Ok(()),
+      Err(e) if keyring_unavailable(&e) => self.fallback(e)?.set(account, secret),
+      Err(e) => Err(Error::Keyring(e.to_string())),
+    }
+  }
+
+  fn delete(&self, account: &str) -> Result<()> {
+    match self.entry(account).and_then(|entry| entry.delete_password()) {
+      Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+      Err(e) if keyring_unavailable(&e) => self.fallback(e)?.delete(account),
+      Err(e) => Err(Error::Keyring(e.to_string())),
+    }
+  }
+
+  fn plaintext_path(&self, account: &str) -> Option<PathBuf> {
+    self.fallback.as_ref()?.plaintext_path(account)
+  }
+}
+
+/* The OS keyring when built with the "keyring" feature, falling back to the file in
+ * CONSTATA_SECRETS_FILE. Without the feature only that file, and nothing when it's not set.
+ */
+pub fn default_secret_store() -> Option<Box<dyn SecretStore>> {
+  #[cfg(feature = "keyring")]
+  return Some(Box::new(KeyringSecretStore::new("constata-cli", FileSecretStore::from_env())));
+
+  #[cfg(not(feature = "keyring"))]
+  return FileSecretStore::from_env().map(|store| Box::new(store) as Box<dyn SecretStore>);
+}
+
+impl Client {
+  /* Like load, but the daily password is read from the first source that has one. */
+  pub fn load_from(
+    custom_config: Option<&str>,
+    profile: Option<&str>,
+    api_url: Option<&str>,
+    sources: &[PasswordSource],
+  ) -> Result<Client> {
+    let password = resolve(sources)?.ok_or(Error::PasswordMissing)?;
+    Self::load(custom_config, profile, api_url, &password)
+  }
+
+  /* Stores the daily password in the secret store, once we know it decrypts the key. */
+  pub fn remember_password(custom_config: Option<&str>, profile: Option<&str>, daily_passphrase: &str) -> Result<()> {
+    let store = default_secret_store().ok_or(Error::NoSecretStore)?;
+    Signature::load(Self::load_config(custom_config, profile, daily_passphrase)?, daily_passphrase)?;
+    store.set(&Self::password_account(custom_config, profile), daily_passphrase)
+  }
+
+  pub fn forget_password(custom_config: Option<&str>, profile: Option<&str>) -> Result<()> {
+    match default_secret_store() {
+      Some(store) => store.delete(&Self::password_account(custom_config, profile)),
+      None => Ok(()),
+    }
+  }
+
+  pub fn password_account(custom_config: Option<&str>, profile: Option<&str>) -> String {
+    account_for(&Self::config_path(custom_config), profile)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn resolves_the_first_available_source() {
+    let path = std::env::temp_dir().join(format!("constata_password_{}", std::process::id()));
+    std::fs::write(&path, "from_file\n").unwrap();
+
+    let sources = vec![
+      PasswordSource::Env("CONSTATA_TEST_UNSET_PASSWORD".to_string()),
+      PasswordSource::File(path.clone()),
+      PasswordSource::Literal("literal".to_string()),
+    ];
+    assert_eq!(resolve(&sources).unwrap().as_deref(), Some("from_file"));
+    assert_eq!(resolve(&sources[..1]).unwrap(), None);
+
+    std::fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn resolves_the_profile_of_secret_accounts() {
+    let path = std::env::temp_dir().join(format!("constata_password_profiles_{}.json", std::process::id()));
+    let path = path.to_str().unwrap();
+    let (config, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    let mut profiles = profiles::Profiles::default();
+    profiles.set(Some("bot"), config.clone());
+    profiles.set(Some("personal"), config);
+    profiles.set_default("bot").unwrap();
+    profiles.save(path).unwrap();
+
+    assert_eq!(account_for(path, None), account_for(path, Some("bot")));
+    assert!(account_for(path, None).ends_with("#bot"));
+    assert_ne!(account_for(path, None), account_for(path, Some("personal")));
+
+    std::fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn stores_secrets_in_a_private_file() {
+    let path = std::env::temp_dir().join(format!("constata_secrets_{}.json", std::process::id()));
+    let store = FileSecretStore::new(&path);
+
+    assert_eq!(store.get("conf#default").unwrap(), None);
+    assert_eq!(store.plaintext_path("conf#default"), None);
+    store.set("conf#default", "not_so_secret").unwrap();
+    assert_eq!(store.plaintext_path("conf#default"), Some(path.clone()));
+    store.set("conf#bot", "bot_secret").unwrap();
+    assert_eq!(store.get("conf#default").unwrap().as_deref(), Some("not_so_secret"));
+
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    store.delete("conf#default").unwrap();
+    assert_eq!(store.get("conf#default").unwrap(), None);
+    assert_eq!(store.get("conf#bot").unwrap().as_deref(), Some("bot_secret"));
+
+    std::fs::remove_file(path).unwrap();
+  }
+
+  #[cfg(feature = "keyring")]
+  #[test]
+  fn keeps_secrets_where_it_can_without_a_keyring_service() {
+    let path = std::env::temp_dir().join(format!("constata_keyring_fallback_{}.json", std::process::id()));
+    let account = format!("constata_test_{}#default", std::process::id());
+    let store = KeyringSecretStore::new("constata-cli-test", Some(FileSecretStore::new(&path)));
+
+    store.set(&account, "not_so_secret").unwrap();
+    assert_eq!(store.get(&account).unwrap().as_deref(), Some("not_so_secret"));
+    store.delete(&account).unwrap();
+    assert_eq!(store.get(&account).unwrap(), None);
+
+    let _ = std::fs::remove_file(path);
+  }
+}
@@ -0,0 +1,173 @@
+use super::*;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_PROFILE: &str = "default";
+
+/* A config file holds one or more named profiles, each with its own key.
+ * Files with a single profile called "default" are stored as a bare Config,
+ * so they stay readable by older versions of this tool.
+ */
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Profiles {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub default_profile: Option<String>,
+  pub profiles: BTreeMap<String, Config>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ConfigFile {
+  Profiles(Profiles),
+  Single(Box<Config>),
+}
+
+impl Profiles {
+  pub fn load(path: &str) -> Result<Profiles> {
+    match serde_json::from_str(&std::fs::read_to_string(path)?)? {
+      ConfigFile::Profiles(profiles) => Ok(profiles),
+      ConfigFile::Single(config) => {
+        let mut profiles = Profiles::default();
+        profiles.profiles.insert(DEFAULT_PROFILE.to_string(), *config);
+        Ok(profiles)
+      },
+    }
+  }
+
+  pub fn load_or_default(path: &str) -> Result<Profiles> {
+    if Path::new(path).exists() {
+      Self::load(path)
+    } else {
+      Ok(Profiles::default())
+    }
+  }
+
+  /* Writes to a temporary file first, so a crash never leaves a half written config behind. */
+  pub fn save(&self, path: &str) -> Result<()> {
+    if let Some(parent) = Path::new(path).parent().filter(|p| !p.as_os_str().is_empty()) {
+      std::fs::create_dir_all(parent)?;
+    }
+
+    let only_default = self.profiles.len() == 1
+      && self.profiles.contains_key(DEFAULT_PROFILE)
+      && self.default_profile.as_deref().is_none_or(|d| d == DEFAULT_PROFILE);
+
+    let contents = if only_default {
+      serde_json::to_string(&self.profiles[DEFAULT_PROFILE])?
+    } else {
+      serde_json::to_string_pretty(self)?
+    };
+
+    let tmp = format!("{}.tmp", path);
+    write_private_file(&tmp, contents.as_bytes())?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+  }
+
+  /* The profile to use when none is given: the configured default,
+   * the only profile in the file, or "default".
+   */
+  pub fn name<'a>(&'a self, profile: Option<&'a str>) -> &'a str {
+    profile
+      .or(self.default_profile.as_deref())
+      .or_else(|| match self.profiles.len() {
+        1 => self.profiles.keys().next().map(|k| k.as_str()),
+        _ => None,
+      })
+      .unwrap_or(DEFAULT_PROFILE)
+  }
+
+  pub fn get(&self, profile: Option<&str>) -> Result<&Config> {
+    let name = self.name(profile);
+    self.profiles.get(name).ok_or_else(|| Error::ProfileNotFound(name.to_string()))
+  }
+
+  pub fn contains(&self, profile: Option<&str>) -> bool {
+    self.profiles.contains_key(self.name(profile))
+  }
+
+  pub fn set(&mut self, profile: Option<&str>, config: Config) {
+    let name = self.name(profile).to_string();
+    self.profiles.insert(name, config);
+  }
+
+  pub fn remove(&mut self, profile: &str) -> Result<Config> {
+    let removed = self.profiles.remove(profile).ok_or_else(|| Error::ProfileNotFound(profile.to_string()))?;
+    if self.default_profile.as_deref() == Some(profile) {
+      self.default_profile = None;
+    }
+    Ok(removed)
+  }
+
+  pub fn set_default(&mut self, profile: &str) -> Result<()> {
+    if !self.profiles.contains_key(profile) {
+      return Err(Error::ProfileNotFound(profile.to_string()));
+    }
+    self.default_profile = Some(profile.to_string());
+    Ok(())
+  }
+}
+
+/* Where config files are looked up, in order:
+ * --config, CONSTATA_CONFIG, ./constata_conf.json and $XDG_CONFIG_HOME/constata/config.json.
+ * When none exists, new configs are created in the XDG location.
+ */
+pub fn discover_config_path(custom_config: Option<&str>) -> String {
+  if let Some(path) = custom_config {
+    return path.to_string();
+  }
+  if let Some(path) = std::env::var_os("CONSTATA_CONFIG").filter(|p| !p.is_empty()) {
+    return path.to_string_lossy().to_string();
+  }
+  if Path::new("constata_conf.json").exists() {
+    return "constata_conf.json".to_string();
+  }
+  match xdg_config_path() {
+    Some(path) => path.to_string_lossy().to_string(),
+    None => "constata_conf.json".to_string(),
+  }
+}
+
+pub(crate) fn xdg_config_path() -> Option<PathBuf> {
+  let base = std::env::var_os("XDG_CONFIG_HOME")
+    .filter(|p| !p.is_empty())
+    .map(PathBuf::from)
+    .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+  Some(base.join("constata").join("config.json"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn keeps_single_profile_files_compatible() {
+    let path = std::env::temp_dir().join(format!("constata_profiles_{}.json", std::process::id()));
+    let path = path.to_str().unwrap();
+    let (main, _) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    let (bot, _) = Signature::create("staging", "very_secret", "not_so_secret").unwrap();
+
+    main.save(path, None).unwrap();
+    let stored: Config = serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+    assert_eq!(stored.public_key(), main.public_key());
+
+    bot.save(path, Some("staging-bot")).unwrap();
+    let mut profiles = Profiles::load(path).unwrap();
+    assert_eq!(profiles.profiles.keys().collect::<Vec<_>>(), vec!["default", "staging-bot"]);
+    assert_eq!(profiles.get(None).unwrap().public_key(), main.public_key());
+    assert_eq!(Config::load(path, Some("staging-bot")).unwrap().public_key(), bot.public_key());
+    assert!(matches!(profiles.get(Some("nope")), Err(Error::ProfileNotFound(_))));
+
+    profiles.set_default("staging-bot").unwrap();
+    profiles.remove("default").unwrap();
+    profiles.save(path).unwrap();
+    assert_eq!(Config::load(path, None).unwrap().public_key(), bot.public_key());
+
+    std::fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn custom_config_wins_discovery() {
+    assert_eq!(discover_config_path(Some("custom.json")), "custom.json");
+  }
+}
@@ -0,0 +1,106 @@
+use super::*;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use zip::{result::ZipError, ZipArchive};
+
+/* Where proofs are saved when no other name is given. */
+pub fn proof_file_name(document_id: &str) -> String {
+  format!("{}.html", document_id)
+}
+
+pub fn each_proof_file_name(document_id: &str) -> String {
+  format!("{}.zip", document_id)
+}
+
+/* Writes a sibling ".part" file and renames it into place,
+ * so a failed or interrupted write never leaves a partial file at `path`.
+ */
+pub fn save<P: AsRef<Path>>(path: P, bytes: &[u8]) -> Result<()> {
+  let path = path.as_ref();
+  let partial = partial_path(path);
+
+  let written = std::fs::write(&partial, bytes).and_then(|_| std::fs::rename(&partial, path));
+  if written.is_err() {
+    let _ = std::fs::remove_file(&partial);
+  }
+  Ok(written?)
+}
+
+/* The sibling file downloads and saves write to before they're complete. */
+pub fn partial_path(path: &Path) -> PathBuf {
+  let mut partial = path.as_os_str().to_owned();
+  partial.push(".part");
+  PathBuf::from(partial)
+}
+
+/* Reads every entry of the ZIP with the per part proofs, checking their CRCs.
+ * A ZIP that was cut short fails here, as its central directory is at the end.
+ */
+pub fn check_zip(bytes: &[u8]) -> Result<()> {
+  let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+  for i in 0..archive.len() {
+    std::io::copy(&mut archive.by_index(i)?, &mut std::io::sink())?;
+  }
+  Ok(())
+}
+
+/* Unpacks the ZIP with the per part proofs into `dir`, returning the paths written. */
+pub fn extract_zip<P: AsRef<Path>>(bytes: &[u8], dir: P) -> Result<Vec<PathBuf>> {
+  let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+  let mut written = vec![];
+
+  for i in 0..archive.len() {
+    let mut entry = archive.by_index(i)?;
+    if entry.is_dir() {
+      continue;
+    }
+    let path = match entry.enclosed_name() {
+      Some(name) => dir.as_ref().join(name),
+      None => return Err(ZipError::InvalidArchive("An entry would be extracted outside the output directory").into()),
+    };
+
+    let mut contents = vec![];
+    entry.read_to_end(&mut contents)?;
+    if let Some(parent) = path.parent() {
+      std::fs::create_dir_all(parent)?;
+    }
+    save(&path, &contents)?;
+    written.push(path);
+  }
+
+  Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Write;
+  use zip::{write::FileOptions, ZipWriter};
+
+  fn zip_of(files: &[(&str, &str)]) -> Vec<u8> {
+    let mut writer = ZipWriter::new(Cursor::new(vec![]));
+    for (name, contents) in files {
+      writer.start_file(*name, FileOptions::default()).unwrap();
+      writer.write_all(contents.as_bytes()).unwrap();
+    }
+    writer.finish().unwrap().into_inner()
+  }
+
+  #[test]
+  fn extracts_each_part_proofs_and_rejects_broken_zips() {
+    let dir = std::env::temp_dir().join(format!("constata_proofs_{}", std::process::id()));
+    let bytes = zip_of(&[("contract.pdf.html", "<html>contract</html>"), ("annex.png.html", "<html>annex</html>")]);
+
+    check_zip(&bytes).unwrap();
+    let written = extract_zip(&bytes, &dir).unwrap();
+    assert_eq!(written, vec![dir.join("contract.pdf.html"), dir.join("annex.png.html")]);
+    assert_eq!(std::fs::read_to_string(dir.join("annex.png.html")).unwrap(), "<html>annex</html>");
+    assert!(!dir.join("annex.png.html.part").exists());
+
+    assert!(matches!(check_zip(&bytes[..bytes.len() - 10]), Err(Error::Zip(_))));
+    assert!(matches!(extract_zip(&zip_of(&[("../escaped.html", "")]), &dir), Err(Error::Zip(_))));
+    assert!(!std::env::temp_dir().join("escaped.html").exists());
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+}
@@ -1,17 +1,16 @@
-use simplestcrypt::{deserialize_and_decrypt, encrypt_and_serialize};
-use std::str::FromStr;
+use simplestcrypt::deserialize_and_decrypt;
 
 use  bitcoin::{
   secp256k1::{self, All, Secp256k1},
   util::{
-    bip32::{DerivationPath, ExtendedPrivKey},
+    bip32::{ChildNumber, DerivationPath, ExtendedPrivKey},
     misc::MessageSignature,
   },
   Address, Network, PrivateKey,
 };
 
 use bitcoin_wallet::{account::MasterKeyEntropy, mnemonic::Mnemonic};
-use crate::signed_payload::SignedPayload;
+use crate::{key_encryption::KeyEncryption, signed_payload::SignedPayload};
 
 use super::*;
 
@@ -24,44 +23,123 @@ impl Signature {
   pub fn create(
     env: &str,
     backup_passphrase: &str,
-    mut daily_passphrase: &str,
+    daily_passphrase: &str,
   ) -> Result<(Config, Mnemonic)> {
-    if daily_passphrase.len() > 32 {
-      daily_passphrase = &daily_passphrase[..32];
+    let mnemonic = Mnemonic::new_random(MasterKeyEntropy::Sufficient)?;
+    let config = Self::config_from_mnemonic(env, &mnemonic, backup_passphrase, daily_passphrase, 0)?;
+    Ok((config, mnemonic))
+  }
+
+  /* Rebuilds the config for the key at `key_index` from the master seed words. */
+  pub fn recover(
+    env: &str,
+    words: &str,
+    backup_passphrase: &str,
+    daily_passphrase: &str,
+    key_index: u32,
+  ) -> Result<Config> {
+    let mnemonic = Mnemonic::from_str(words)?;
+    Self::config_from_mnemonic(env, &mnemonic, backup_passphrase, daily_passphrase, key_index)
+  }
+
+  /* Replaces the signing key in `config` with the next one derived from the master seed,
+   * keeping its api_url and network settings.
+   * Returns the new config and a statement announcing the rotation, signed with the
+   * current key, which is the one the server knows belongs to the account. Whoever
+   * compromised that key could sign one too, so constata confirms it by email before revoking.
+   */
+  pub fn rotate(
+    config: &Config,
+    words: &str,
+    backup_passphrase: &str,
+    daily_passphrase: &str,
+  ) -> Result<(Config, SignedPayload)> {
+    let mnemonic = Mnemonic::from_str(words)?;
+    let current = Self::derive(&mnemonic, backup_passphrase, config.key_index)?;
+    if current.public_key(&Secp256k1::new()) != config.public_key {
+      return Err(Error::SeedMismatch);
     }
 
-    let mnemonic = Mnemonic::new_random(MasterKeyEntropy::Sufficient)?;
+    let key_index = config.key_index + 1;
+    let rotated = Config {
+      api_url: config.api_url.clone(),
+      network: config.network.clone(),
+      ..Self::config_from_mnemonic(&config.environment, &mnemonic, backup_passphrase, daily_passphrase, key_index)?
+    };
+
+    let statement = serde_json::json!({
+      "constata_eu_action": "rotate_key",
+      "previous_public_key": config.public_key.to_string(),
+      "public_key": rotated.public_key.to_string(),
+      "key_index": key_index,
+      "date": chrono::offset::Utc::now(),
+    })
+    .to_string();
+    let signed_statement = Signature { key: current }.sign_message(statement.as_bytes());
+
+    Ok((rotated, signed_statement))
+  }
+
+  /* Re-encrypts the same key under a new daily passphrase. */
+  pub fn change_password(config: Config, old_daily_passphrase: &str, new_daily_passphrase: &str) -> Result<Config> {
+    let signature = Signature::load(config.clone(), old_daily_passphrase)?;
+    let (encrypted_key, encryption) = KeyEncryption::encrypt(signature.key.to_wif().as_bytes(), new_daily_passphrase)?;
+    Ok(Config {
+      version: CURRENT_CONFIG_VERSION,
+      encrypted_key,
+      encryption: Some(encryption),
+      ..config
+    })
+  }
+
+  /* Index 0 is m/44'/80'/80', the key every config was created with.
+   * Rotated keys are its siblings, m/44'/80'/81' and so on, each on its own branch.
+   */
+  pub fn key_path(key_index: u32) -> Result<DerivationPath> {
+    let account = 80u32.checked_add(key_index).ok_or(bitcoin::util::bip32::Error::InvalidChildNumber(key_index))?;
+    Ok(DerivationPath::from(vec![
+      ChildNumber::from_hardened_idx(44)?,
+      ChildNumber::from_hardened_idx(80)?,
+      ChildNumber::from_hardened_idx(account)?,
+    ]))
+  }
+
+  fn derive(mnemonic: &Mnemonic, backup_passphrase: &str, key_index: u32) -> Result<PrivateKey> {
     let seed = mnemonic.to_seed(Some(backup_passphrase));
     let context: Secp256k1<All> = Secp256k1::new();
     let master_key = ExtendedPrivKey::new_master(Network::Bitcoin, &seed.0)?;
-    let for_signing = master_key.derive_priv(
-      &context,
-      &DerivationPath::from_str("m/44'/80'/80'").unwrap(),
-    )?;
-    let public_key = for_signing.private_key.public_key(&context);
-
-    let encrypted_key = encrypt_and_serialize(
-      &daily_passphrase.as_bytes(),
-      for_signing.private_key.to_wif().as_bytes(),
-    )
-    .map_err(|_| Error::DailyKeyEncriptionError)?;
+    Ok(master_key.derive_priv(&context, &Self::key_path(key_index)?)?.private_key)
+  }
+
+  fn config_from_mnemonic(
+    env: &str,
+    mnemonic: &Mnemonic,
+    backup_passphrase: &str,
+    daily_passphrase: &str,
+    key_index: u32,
+  ) -> Result<Config> {
+    let for_signing = Self::derive(mnemonic, backup_passphrase, key_index)?;
+    let (encrypted_key, encryption) = KeyEncryption::encrypt(for_signing.to_wif().as_bytes(), daily_passphrase)?;
 
-    Ok((
-      Config {
-        encrypted_key,
-        public_key,
-        environment: env.to_string(),
-      },
-      mnemonic,
-    ))
+    Ok(Config {
+      version: CURRENT_CONFIG_VERSION,
+      encrypted_key,
+      encryption: Some(encryption),
+      public_key: for_signing.public_key(&Secp256k1::new()),
+      environment: env.to_string(),
+      api_url: None,
+      key_index,
+      registered_at: None,
+      network: Default::default(),
+    })
   }
 
   pub fn load(stored_key: Config, daily_passphrase: &str) -> Result<Signature> {
-    let decrypted = deserialize_and_decrypt(daily_passphrase.as_bytes(), &stored_key.encrypted_key)
-      .unwrap_or_else(|_| {
-        eprintln!("\n {} Password incorrect\n", Emoji("🚨", "*"));
-        std::process::exit(1); // Exit with code 1 (fail)
-      });
+    let decrypted = match &stored_key.encryption {
+      Some(encryption) => encryption.decrypt(&stored_key.encrypted_key, daily_passphrase)?,
+      None => deserialize_and_decrypt(daily_passphrase.as_bytes(), &stored_key.encrypted_key)
+        .map_err(|_| Error::WrongPassword)?,
+    };
 
     let key = PrivateKey::from_wif(&String::from_utf8(decrypted)?)?;
 
@@ -79,21 +157,35 @@ impl Signature {
     )
   }
 
-  pub fn sign_message(&self, payload: &[u8]) -> SignedPayload {
-    let secp = secp256k1::Secp256k1::new();
-    let msg_hash = SignedPayload::signed_msg_hash(payload);
-    let msg = secp256k1::Message::from_slice(&msg_hash).unwrap();
+  /* Signed token sent in the Authentication header of GET requests.
+   * It's only valid for the given url and expires in 100 hours.
+   */
+  pub fn auth_token(&self, url: &str) -> Result<String> {
+    let payload = serde_json::json![{
+      "constata_eu_action": url,
+      "expires": chrono::offset::Utc::now() + chrono::Duration::hours(100),
+    }]
+    .to_string();
 
-    let secp_sig = secp.sign_recoverable(&msg, &self.key.key);
-    let signature = MessageSignature {
-      signature: secp_sig,
-      compressed: self.key.compressed,
-    };
+    Ok(serde_json::to_string(&self.sign_message(payload.as_bytes()))?)
+  }
 
+  pub fn sign_message(&self, payload: &[u8]) -> SignedPayload {
     SignedPayload {
       payload: payload.to_vec(),
       signer: self.public_key(),
-      signature,
+      signature: self.sign_hash(SignedPayload::signed_msg_hash(payload)),
+    }
+  }
+
+  /* Signs a hash built with SignedPayload::signed_msg_hash or signed_msg_hash_reader. */
+  pub fn sign_hash(&self, msg_hash: bitcoin::hashes::sha256d::Hash) -> MessageSignature {
+    let secp = secp256k1::Secp256k1::new();
+    let msg = secp256k1::Message::from_slice(&msg_hash).unwrap();
+
+    MessageSignature {
+      signature: secp.sign_recoverable(&msg, &self.key.key),
+      compressed: self.key.compressed,
     }
   }
 }
@@ -117,4 +209,82 @@ mod tests {
     let signed_payload = signature.sign_message(b"good_bye");
     assert!(signed_payload.signed_ok().unwrap());
   }
+
+  #[test]
+  fn migrates_legacy_configs() {
+    let legacy: Config = serde_json::from_str("{\"public_key\":\"0203846a050544f640b3a4cf512011d73555f7b267511d3490b6f1d2deab981a3d\",\"encrypted_key\":\"85c0b2b00da46ca0e75b9bb372c571c244000000000000009e956444f91f695a759137b1e783f893f1cd8091d5c6306bf26c310284b501f21a0c99643d00b2d823f3446b93e5f0d74687acb4749e5ffd3f4be02ad356f1afdd5ed861\",\"environment\":\"development\"}").unwrap();
+    assert_eq!(legacy.version, 1);
+    assert!(legacy.needs_migration());
+
+    let migrated = Signature::change_password(legacy.clone(), "not_so_secret", "not_so_secret").unwrap();
+    assert_eq!(migrated.version, CURRENT_CONFIG_VERSION);
+    assert!(!migrated.needs_migration());
+    assert_eq!(migrated.public_key, legacy.public_key);
+    Signature::load(migrated, "not_so_secret").unwrap();
+  }
+
+  #[test]
+  fn recovers_and_rotates_keys_from_the_master_seed() {
+    let (config, mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    let words = mnemonic.iter().map(|w| w.to_string()).collect::<Vec<_>>().join(" ");
+
+    let recovered = Signature::recover("production", &words, "very_secret", "new_daily", 0).unwrap();
+    assert_eq!(recovered.public_key, config.public_key);
+    Signature::load(recovered, "new_daily").unwrap();
+
+    let (rotated, statement) = Signature::rotate(&config, &words, "very_secret", "rotated_daily").unwrap();
+    assert_eq!(rotated.key_index, 1);
+    assert_ne!(rotated.public_key, config.public_key);
+    assert!(statement.signed_ok().unwrap());
+    assert_eq!(statement.signer, Signature::load(config.clone(), "not_so_secret").unwrap().public_key());
+    let body: serde_json::Value = serde_json::from_slice(&statement.payload).unwrap();
+    assert_eq!(body["public_key"], rotated.public_key.to_string());
+    Signature::load(rotated, "rotated_daily").unwrap();
+
+    assert!(matches!(Signature::rotate(&config, &words, "wrong_secret", "x"), Err(Error::SeedMismatch)));
+  }
+
+  #[test]
+  fn derives_rotated_keys_on_sibling_branches() {
+    assert_eq!(Signature::key_path(0).unwrap().to_string(), "m/44'/80'/80'");
+    assert_eq!(Signature::key_path(1).unwrap().to_string(), "m/44'/80'/81'");
+    assert_eq!(Signature::key_path(7).unwrap().to_string(), "m/44'/80'/87'");
+    assert!(Signature::key_path(u32::MAX).is_err());
+    assert!(Signature::key_path(1 << 31).is_err());
+  }
+
+  #[test]
+  fn rotating_keeps_the_custom_environment() {
+    let (mut config, mnemonic) = Signature::create("staging-mirror", "very_secret", "not_so_secret").unwrap();
+    config.set_api_url(Some("https://constata.internal.corp/api")).unwrap();
+    let network = network::NetworkSettings {
+      proxy: Some("http://proxy.internal.corp:3128".to_string()),
+      ca_cert: Some("corp_ca.pem".to_string()),
+      ..Default::default()
+    };
+    config.set_network(network.clone());
+    let words = mnemonic.iter().map(|w| w.to_string()).collect::<Vec<_>>().join(" ");
+
+    let (rotated, _statement) = Signature::rotate(&config, &words, "very_secret", "rotated_daily").unwrap();
+    assert_eq!(rotated.environment(), "staging-mirror");
+    assert_eq!(rotated.api_url(None).unwrap(), "https://constata.internal.corp/api");
+    assert_eq!(rotated.network(), &network);
+  }
+
+  #[test]
+  fn changes_the_daily_password_keeping_the_key() {
+    let (config, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    assert!(matches!(Signature::change_password(config.clone(), "wrong", "new_secret"), Err(Error::WrongPassword)));
+
+    let changed = Signature::change_password(config.clone(), "not_so_secret", "new_secret").unwrap();
+    assert_eq!(changed.public_key, config.public_key);
+    assert!(matches!(Signature::load(changed.clone(), "not_so_secret"), Err(Error::WrongPassword)));
+    Signature::load(changed, "new_secret").unwrap();
+  }
+
+  #[test]
+  fn wrong_password_is_an_error() {
+    let (config, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    assert!(matches!(Signature::load(config, "wrong"), Err(Error::WrongPassword)));
+  }
 }
@@ -13,6 +13,7 @@ base64_serde_type!(Base64Standard, base64::STANDARD);
 
 use serde_with::{serde_as, DisplayFromStr};
 use sha2::{Digest, Sha256};
+use std::io::Read;
 
 pub fn hexdigest(bytes: &[u8]) -> String {
   let mut hasher = Sha256::new();
@@ -40,6 +41,41 @@ impl SignedPayload {
     sha256d::Hash::from_engine(engine)
   }
 
+  /* Same as signed_msg_hash, for messages too big to hold in memory.
+   * Reads exactly `len` bytes in chunks and also returns their plain sha256.
+   */
+  pub fn signed_msg_hash_reader<R: Read>(
+    len: u64,
+    reader: &mut R,
+    mut on_chunk: impl FnMut(u64),
+  ) -> std::io::Result<(sha256d::Hash, String)> {
+    let mut engine = sha256d::Hash::engine();
+    engine.input(BITCOIN_SIGNED_MSG_PREFIX);
+    encode::VarInt(len).consensus_encode(&mut engine)?;
+
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut done = 0u64;
+    loop {
+      let read = match reader.read(&mut buffer) {
+        Ok(0) => break,
+        Ok(read) => read,
+        Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+        Err(e) => return Err(e),
+      };
+      engine.input(&buffer[..read]);
+      hasher.update(&buffer[..read]);
+      done += read as u64;
+      on_chunk(done);
+    }
+
+    if done != len {
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "the file changed while it was being read"));
+    }
+
+    Ok((sha256d::Hash::from_engine(engine), format!("{:x}", hasher.finalize())))
+  }
+
   pub fn payload_hash(&self) -> String {
     hexdigest(&self.payload)
   }
@@ -49,11 +85,11 @@ impl SignedPayload {
   }
 
   pub fn signed_ok(&self) -> Result<bool, bitcoin::secp256k1::Error> {
-    Ok(self.signature.is_signed_by_address(
+    self.signature.is_signed_by_address(
       &secp256k1::Secp256k1::new(),
       &self.signer,
       SignedPayload::signed_msg_hash(&self.payload),
-    )?)
+    )
   }
 }
 
@@ -72,6 +108,19 @@ mod tests {
     assert_eq!(signed_payload.payload, b"hello world".to_vec());
   }
 
+  #[test]
+  fn hashes_readers_like_in_memory_messages() {
+    let message = vec![7u8; 200_000];
+    let mut chunks = 0;
+    let (msg_hash, sha256) =
+      SignedPayload::signed_msg_hash_reader(message.len() as u64, &mut &message[..], |_| chunks += 1).unwrap();
+
+    assert_eq!(msg_hash, SignedPayload::signed_msg_hash(&message));
+    assert_eq!(sha256, hexdigest(&message));
+    assert!(chunks > 1);
+    assert!(SignedPayload::signed_msg_hash_reader(10, &mut &message[..], |_| ()).is_err());
+  }
+
   #[test]
   fn deserializes_bad_signatures_too() {
     let signed_payload: SignedPayload = serde_json::from_str(r#"{
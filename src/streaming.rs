@@ -0,0 +1,252 @@
+use super::*;
+use bitcoin::{util::misc::MessageSignature, Address};
+use sha2::{Digest, Sha256};
+use signed_payload::SignedPayload;
+use std::cell::Cell;
+use std::io::{Cursor, Read};
+
+/* Stamps files too big to hold in memory.
+ * The file is read twice: once to hash and sign it, and once more while uploading,
+ * base64 encoding it on the fly into the same JSON body sign_and_timestamp would send.
+ * The upload hashes the file again and is aborted before it completes if the file
+ * changed in between, so the API never gets content that doesn't match its signature.
+ * The API can't tell streamed and in memory stamps apart.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+  Hashing,
+  Uploading,
+}
+
+/* A SignedPayload whose payload stays on disk. */
+#[derive(Debug, Clone)]
+pub struct StreamedPayload {
+  pub path: String,
+  pub size: u64,
+  pub sha256: String,
+  pub signer: Address,
+  pub signature: MessageSignature,
+}
+
+impl StreamedPayload {
+  /* The JSON body for POST /documents/, as a reader that never holds the whole file.
+   * Sets `changed` and fails instead of finishing the body if the file is not the one signed.
+   */
+  fn body<'a>(&'a self, changed: &'a Cell<bool>, on_progress: impl FnMut(u64) + 'a) -> Result<impl Read + 'a> {
+    let head = r#"{"signed_payload":{"payload":""#.to_string();
+    let tail = format!(
+      r#"","signer":{},"signature":{}}}}}"#,
+      serde_json::to_string(&self.signer.to_string())?,
+      serde_json::to_string(&self.signature.to_string())?,
+    );
+    let file = ProgressReader { inner: open_document_file(&self.path)?, done: 0, on_progress };
+    let file = CheckedReader { inner: file, hasher: Sha256::new(), read: 0, checked: false, expected: self, changed };
+
+    Ok(Cursor::new(head).chain(Base64Reader::new(file)).chain(Cursor::new(tail)))
+  }
+}
+
+const BASE64_CHUNK: usize = 3 * 64 * 1024;
+
+/* Base64 encodes a reader in chunks whose length is a multiple of 3, so they concatenate without padding. */
+struct Base64Reader<R> {
+  inner: R,
+  input: Vec<u8>,
+  encoded: Vec<u8>,
+  position: usize,
+}
+
+impl<R: Read> Base64Reader<R> {
+  fn new(inner: R) -> Self {
+    Base64Reader { inner, input: vec![0u8; BASE64_CHUNK], encoded: vec![], position: 0 }
+  }
+
+  fn fill(&mut self) -> std::io::Result<()> {
+    let mut filled = 0;
+    while filled < BASE64_CHUNK {
+      match self.inner.read(&mut self.input[filled..]) {
+        Ok(0) => break,
+        Ok(read) => filled += read,
+        Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+        Err(e) => return Err(e),
+      }
+    }
+    self.encoded = base64::encode(&self.input[..filled]).into_bytes();
+    self.position = 0;
+    Ok(())
+  }
+}
+
+impl<R: Read> Read for Base64Reader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    if self.position == self.encoded.len() {
+      self.fill()?;
+    }
+    let available = &self.encoded[self.position..];
+    let len = available.len().min(buf.len());
+    buf[..len].copy_from_slice(&available[..len]);
+    self.position += len;
+    Ok(len)
+  }
+}
+
+struct ProgressReader<R, F> {
+  inner: R,
+  done: u64,
+  on_progress: F,
+}
+
+impl<R: Read, F: FnMut(u64)> Read for ProgressReader<R, F> {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    let read = self.inner.read(buf)?;
+    self.done += read as u64;
+    (self.on_progress)(self.done);
+    Ok(read)
+  }
+}
+
+/* Hashes what it reads, and at the end of the file fails unless it read what was signed.
+ * The check is done once, reading again after the end of the file just returns nothing.
+ */
+struct CheckedReader<'a, R> {
+  inner: R,
+  hasher: Sha256,
+  read: u64,
+  checked: bool,
+  expected: &'a StreamedPayload,
+  changed: &'a Cell<bool>,
+}
+
+impl<'a, R: Read> Read for CheckedReader<'a, R> {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    if self.checked {
+      return Ok(0);
+    }
+    let read = self.inner.read(buf)?;
+    self.hasher.update(&buf[..read]);
+    self.read += read as u64;
+
+    if read == 0 || self.read > self.expected.size {
+      let sha256 = format!("{:x}", self.hasher.finalize_reset());
+      if self.read != self.expected.size || sha256 != self.expected.sha256 {
+        self.changed.set(true);
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "the file changed after it was signed"));
+      }
+      self.checked = true;
+    }
+    Ok(read)
+  }
+}
+
+impl Client {
+  /* Signs a file reading it in chunks. `on_progress` gets the bytes hashed so far. */
+  pub fn sign_path_streaming(&self, path: &str, on_progress: impl FnMut(u64)) -> Result<StreamedPayload> {
+    let mut file = open_document_file(path)?;
+    let size = file.metadata()?.len();
+    let (msg_hash, sha256) = SignedPayload::signed_msg_hash_reader(size, &mut file, on_progress)?;
+
+    Ok(StreamedPayload {
+      path: path.to_string(),
+      size,
+      sha256,
+      signer: self.signature.public_key(),
+      signature: self.signature.sign_hash(msg_hash),
+    })
+  }
+
+  /* Progress starts over if the upload has to be retried. */
+  pub fn submit_streaming(&self, streamed: &StreamedPayload, on_progress: impl FnMut(u64)) -> Result<DocumentBundle> {
+    let url = format!("{}/documents/", self.api_url);
+    let on_progress = &std::cell::RefCell::new(on_progress);
+    let changed = &Cell::new(false);
+    let result = self.transport.post_reader(&url, "application/json", || {
+      streamed.body(changed, move |done| (on_progress.borrow_mut())(done))
+    });
+
+    match result {
+      Err(_) if changed.get() => Err(Error::FileChanged(streamed.path.clone())),
+      Ok(response) => Ok(response.into_json()?),
+      Err(Error::Api { status: 422, body }) => Err(Error::already_stamped(&body)),
+      Err(err) => Err(err),
+    }
+  }
+
+  /* Like sign_and_timestamp_path, with memory use independent of the file size.
+   * `on_progress` gets the phase, the bytes processed so far and the file size.
+   */
+  pub fn sign_and_timestamp_path_streaming(
+    &self,
+    path: &str,
+    mut on_progress: impl FnMut(Phase, u64, u64),
+  ) -> Result<DocumentBundle> {
+    let size = open_document_file(path)?.metadata()?.len();
+    let streamed = self.sign_path_streaming(path, |done| on_progress(Phase::Hashing, done, size))?;
+    let document = self.submit_streaming(&streamed, |done| on_progress(Phase::Uploading, done, size))?;
+    self.record_in_ledger(path, &streamed.sha256, &document);
+    Ok(document)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use mockito;
+
+  #[test]
+  fn encodes_base64_across_chunks() {
+    let bytes: Vec<u8> = (0..BASE64_CHUNK * 2 + 7).map(|i| (i % 251) as u8).collect();
+    let mut encoded = String::new();
+    Base64Reader::new(&bytes[..]).read_to_string(&mut encoded).unwrap();
+    assert_eq!(encoded, base64::encode(&bytes));
+  }
+
+  #[test]
+  fn streams_the_same_payload_as_in_memory_stamps() {
+    let path = std::env::temp_dir().join(format!("constata_streaming_{}.bin", std::process::id()));
+    let bytes: Vec<u8> = (0..BASE64_CHUNK + 11).map(|i| (i % 13) as u8).collect();
+    std::fs::write(&path, &bytes).unwrap();
+    let path = path.to_str().unwrap();
+
+    let (config, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    let signature = Signature::load(config, "not_so_secret").unwrap();
+    let expected = signature.sign_message(&bytes);
+    let client = Client { signature, api_url: mockito::server_url(), ledger: None, transport: transport::default_transport() };
+
+    let mock = mockito::mock("POST", "/documents/")
+        .match_body(mockito::Matcher::Json(serde_json::json!({ "signed_payload": &expected })))
+        .with_status(200)
+        .with_body(r#"{"state":"Parked","id":"1-3","person_id":1,"bulletin_id":null,"parts":[],"created_at":"2022-01-05T08:04:47.166681Z","cost":"1","gift_id":null,"bulletins":{},"buy_tokens_link":null}"#)
+        .expect(1)
+        .create();
+
+    let mut uploaded = 0;
+    let document = client
+      .sign_and_timestamp_path_streaming(path, |phase, done, _| if phase == Phase::Uploading { uploaded = done })
+      .unwrap();
+    assert_eq!(document.id, "1-3");
+    assert_eq!(uploaded, bytes.len() as u64);
+
+    mock.assert();
+    std::fs::remove_file(path).unwrap();
+  }
+
+  #[test]
+  fn aborts_uploads_of_files_changed_after_signing() {
+    let path = std::env::temp_dir().join(format!("constata_streaming_changed_{}.txt", std::process::id()));
+    std::fs::write(&path, b"the signed contract").unwrap();
+    let path = path.to_str().unwrap();
+
+    let (config, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    let signature = Signature::load(config, "not_so_secret").unwrap();
+    let client = Client { signature, api_url: mockito::server_url(), ledger: None, transport: transport::default_transport() };
+
+    let streamed = client.sign_path_streaming(path, |_| ()).unwrap();
+    std::fs::write(path, b"the forged contract").unwrap();
+    assert!(matches!(client.submit_streaming(&streamed, |_| ()), Err(Error::FileChanged(_))));
+
+    std::fs::write(path, b"the signed contract, with more pages").unwrap();
+    assert!(matches!(client.submit_streaming(&streamed, |_| ()), Err(Error::FileChanged(_))));
+
+    std::fs::remove_file(path).unwrap();
+  }
+}
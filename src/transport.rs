@@ -0,0 +1,640 @@
+use super::*;
+use std::collections::VecDeque;
+use std::io::{Cursor, Read, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/* Everything Client sends to the API goes through a Transport.
+ * Implementations only move bytes around: any response, whatever its status,
+ * is a successful send. Client turns error statuses into Error::Api.
+ */
+pub trait Transport: Send + Sync {
+  fn send(&self, request: &mut HttpRequest) -> Result<HttpResponse>;
+}
+
+impl<T: Transport + ?Sized> Transport for std::sync::Arc<T> {
+  fn send(&self, request: &mut HttpRequest) -> Result<HttpResponse> {
+    (**self).send(request)
+  }
+}
+
+pub struct HttpRequest<'a> {
+  pub method: &'static str,
+  pub url: String,
+  pub headers: Vec<(String, String)>,
+  pub body: Body<'a>,
+}
+
+pub enum Body<'a> {
+  Empty,
+  Json(serde_json::Value),
+  /* Opened again on every attempt, since a reader can only be sent once. */
+  Reader {
+    content_type: String,
+    open: Box<dyn FnMut() -> Result<Box<dyn Read + 'a>> + 'a>,
+  },
+}
+
+impl<'a> HttpRequest<'a> {
+  pub fn new(method: &'static str, url: &str, body: Body<'a>) -> HttpRequest<'a> {
+    HttpRequest { method, url: url.to_string(), headers: vec![], body }
+  }
+
+  pub fn header(mut self, name: &str, value: &str) -> HttpRequest<'a> {
+    self.headers.push((name.to_string(), value.to_string()));
+    self
+  }
+}
+
+pub struct HttpResponse {
+  pub status: u16,
+  pub headers: Vec<(String, String)>,
+  body: Box<dyn Read + Send>,
+}
+
+impl HttpResponse {
+  pub fn new<R: Read + Send + 'static>(status: u16, headers: Vec<(String, String)>, body: R) -> HttpResponse {
+    HttpResponse { status, headers, body: Box::new(body) }
+  }
+
+  pub fn header(&self, name: &str) -> Option<&str> {
+    self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+  }
+
+  pub fn is_success(&self) -> bool {
+    (200..300).contains(&self.status)
+  }
+
+  pub fn into_reader(self) -> Box<dyn Read + Send> {
+    self.body
+  }
+
+  pub fn content_length(&self) -> Option<u64> {
+    self.header("Content-Length").and_then(|l| l.trim().parse().ok())
+  }
+
+  /* Fails with Error::Truncated when the body is shorter than its Content-Length. */
+  pub fn into_bytes(self) -> Result<Vec<u8>> {
+    self.into_bytes_with(|_, _| ())
+  }
+
+  /* `on_progress` gets the bytes read so far, and the Content-Length if there was one. */
+  pub fn into_bytes_with(self, on_progress: impl FnMut(u64, Option<u64>)) -> Result<Vec<u8>> {
+    let mut bytes: Vec<u8> = vec![];
+    self.write_to_with(&mut bytes, on_progress)?;
+    Ok(bytes)
+  }
+
+  /* Like into_bytes_with, writing the body to `out` as it arrives. Returns how many bytes were written,
+   * which were written even if it then fails with Error::Truncated.
+   */
+  pub fn write_to_with<W: Write>(mut self, out: &mut W, mut on_progress: impl FnMut(u64, Option<u64>)) -> Result<u64> {
+    let expected = self.content_length();
+    let mut written = 0u64;
+    let mut chunk = [0u8; 64 * 1024];
+    loop {
+      let read = match self.body.read(&mut chunk) {
+        Ok(0) => break,
+        Ok(read) => read,
+        Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+        Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof && expected.is_some() => break,
+        Err(e) => return Err(e.into()),
+      };
+      out.write_all(&chunk[..read])?;
+      written += read as u64;
+      on_progress(written, expected);
+    }
+
+    match expected {
+      Some(expected) if expected != written => Err(Error::Truncated { expected, received: written }),
+      _ => Ok(written),
+    }
+  }
+
+  pub fn into_string(self) -> Result<String> {
+    Ok(String::from_utf8(self.into_bytes()?)?)
+  }
+
+  pub fn into_json<T: serde::de::DeserializeOwned>(self) -> Result<T> {
+    Ok(serde_json::from_reader(self.body)?)
+  }
+
+  pub fn error_for_status(self) -> Result<HttpResponse> {
+    if self.is_success() {
+      Ok(self)
+    } else {
+      let status = self.status;
+      Err(Error::Api { status, body: self.into_string().unwrap_or_default() })
+    }
+  }
+}
+
+/* Shortcuts for the requests Client makes. Error statuses become Error::Api. */
+impl dyn Transport + '_ {
+  pub fn get(&self, url: &str, headers: &[(&str, &str)]) -> Result<HttpResponse> {
+    let mut request = HttpRequest::new("GET", url, Body::Empty);
+    for (name, value) in headers {
+      request = request.header(name, value);
+    }
+    self.send(&mut request)?.error_for_status()
+  }
+
+  pub fn post_json(&self, url: &str, body: &serde_json::Value) -> Result<HttpResponse> {
+    self.send(&mut HttpRequest::new("POST", url, Body::Json(body.clone())))?.error_for_status()
+  }
+
+  pub fn post_reader<'a, R: Read + 'a>(
+    &self,
+    url: &str,
+    content_type: &str,
+    mut open: impl FnMut() -> Result<R> + 'a,
+  ) -> Result<HttpResponse> {
+    let body = Body::Reader {
+      content_type: content_type.to_string(),
+      open: Box::new(move || Ok(Box::new(open()?) as Box<dyn Read + 'a>)),
+    };
+    self.send(&mut HttpRequest::new("POST", url, body))?.error_for_status()
+  }
+}
+
+/* How long to wait for the API, and how to retry when it's unavailable.
+ * GET requests are retried on connection errors and 5xx responses, with exponential backoff and jitter.
+ * Other requests may have been stored by the API even if the response was lost, so they're only
+ * retried when they never reached it: DNS and connect failures, and 429 responses.
+ * 429 responses are retried after the time the API asks for in Retry-After, up to max_backoff.
+ * Downloads cut short are not retried here, Client::download_each_proof_with resumes them on its next call.
+ */
+#[derive(Debug, Clone)]
+pub struct TransportOptions {
+  pub connect_timeout: Duration,
+  pub read_timeout: Duration,
+  pub max_attempts: u32,
+  pub initial_backoff: Duration,
+  pub max_backoff: Duration,
+}
+
+impl Default for TransportOptions {
+  fn default() -> Self {
+    TransportOptions {
+      connect_timeout: Duration::from_secs(10),
+      read_timeout: Duration::from_secs(120),
+      max_attempts: 4,
+      initial_backoff: Duration::from_millis(500),
+      max_backoff: Duration::from_secs(30),
+    }
+  }
+}
+
+impl TransportOptions {
+  /* Half the exponential delay is fixed, the other half is random, so parallel clients spread out. */
+  pub fn backoff(&self, attempt: u32) -> Duration {
+    let exponential = self.initial_backoff.checked_mul(1 << attempt.min(16)).unwrap_or(self.max_backoff);
+    let delay = exponential.min(self.max_backoff);
+    delay / 2 + delay.mul_f64(rand::random::<f64>() / 2.0)
+  }
+}
+
+/* The default transport: ureq with timeouts, retrying as TransportOptions says. */
+pub fn http(options: TransportOptions) -> Retrying<UreqTransport> {
+  Retrying::new(UreqTransport::from_options(&options), options)
+}
+
+/* Like http, using the proxy and TLS settings for talking to `url`. */
+pub fn http_with(options: TransportOptions, settings: &network::NetworkSettings, url: &str) -> Result<Retrying<UreqTransport>> {
+  Ok(Retrying::new(UreqTransport::new(settings.agent(url, &options)?), options))
+}
+
+#[cfg(test)]
+pub(crate) fn default_transport() -> Box<dyn Transport> {
+  Box::new(http(TransportOptions::default()))
+}
+
+/* Build your own ureq::Agent to add proxies or client certificates. */
+pub struct UreqTransport {
+  agent: ureq::Agent,
+}
+
+impl UreqTransport {
+  pub fn new(agent: ureq::Agent) -> UreqTransport {
+    UreqTransport { agent }
+  }
+
+  pub fn from_options(options: &TransportOptions) -> UreqTransport {
+    Self::new(ureq::Agent::new_with_config(agent_config(options).build()))
+  }
+}
+
+/* Agent settings shared by every ureq transport. The read timeout bounds how long the API
+ * takes to answer, bodies can take longer so big uploads and downloads aren't cut.
+ * Error statuses are responses like any other, and proxies only come from NetworkSettings.
+ */
+pub(crate) fn agent_config(options: &TransportOptions) -> ureq::config::ConfigBuilder<ureq::typestate::AgentScope> {
+  ureq::config::Config::builder()
+    .timeout_connect(Some(options.connect_timeout))
+    .timeout_send_request(Some(options.read_timeout))
+    .timeout_recv_response(Some(options.read_timeout))
+    .http_status_as_error(false)
+    .proxy(None)
+}
+
+impl Transport for UreqTransport {
+  fn send(&self, request: &mut HttpRequest) -> Result<HttpResponse> {
+    let mut call = ureq::http::Request::builder().method(request.method).uri(&request.url);
+    for (name, value) in &request.headers {
+      call = call.header(name.as_str(), value.as_str());
+    }
+    let invalid = |err: ureq::http::Error| Error::Network(Box::new(err.into()));
+
+    let result = match &mut request.body {
+      Body::Empty => self.agent.run(call.body(()).map_err(invalid)?),
+      Body::Json(value) => {
+        let json = serde_json::to_vec(value)?;
+        self.agent.run(call.header("Content-Type", "application/json").body(json).map_err(invalid)?)
+      },
+      Body::Reader { content_type, open } => {
+        let mut reader = open()?;
+        let body = ureq::SendBody::from_reader(&mut reader);
+        self.agent.run(call.header("Content-Type", content_type.as_str()).body(body).map_err(invalid)?)
+      },
+    };
+    let response = result.map_err(|err| Error::Network(Box::new(err)))?;
+
+    let headers = response
+      .headers()
+      .iter()
+      .filter_map(|(name, value)| value.to_str().ok().map(|v| (name.to_string(), v.to_string())))
+      .collect();
+    Ok(HttpResponse::new(response.status().as_u16(), headers, response.into_body().into_reader()))
+  }
+}
+
+/* A blocking reqwest transport, for applications that already configure reqwest.
+ * Reader bodies are buffered in memory before sending.
+ */
+#[cfg(feature = "blocking-reqwest")]
+pub struct ReqwestTransport {
+  client: reqwest::blocking::Client,
+}
+
+#[cfg(feature = "blocking-reqwest")]
+impl ReqwestTransport {
+  pub fn new(client: reqwest::blocking::Client) -> ReqwestTransport {
+    ReqwestTransport { client }
+  }
+}
+
+#[cfg(feature = "blocking-reqwest")]
+impl Transport for ReqwestTransport {
+  fn send(&self, request: &mut HttpRequest) -> Result<HttpResponse> {
+    let method = reqwest::Method::from_bytes(request.method.as_bytes()).expect("HTTP methods to be valid");
+    let mut call = self.client.request(method, &request.url);
+    for (name, value) in &request.headers {
+      call = call.header(name.as_str(), value.as_str());
+    }
+
+    call = match &mut request.body {
+      Body::Empty => call,
+      Body::Json(value) => call.json(value),
+      Body::Reader { content_type, open } => {
+        let mut bytes = vec![];
+        open()?.read_to_end(&mut bytes)?;
+        call.header("Content-Type", content_type.as_str()).body(bytes)
+      },
+    };
+
+    let response = call.send()?;
+    let headers = response
+      .headers()
+      .iter()
+      .filter_map(|(name, value)| value.to_str().ok().map(|v| (name.to_string(), v.to_string())))
+      .collect();
+    Ok(HttpResponse::new(response.status().as_u16(), headers, response))
+  }
+}
+
+pub struct Retrying<T> {
+  inner: T,
+  options: TransportOptions,
+}
+
+impl<T: Transport> Retrying<T> {
+  pub fn new(inner: T, options: TransportOptions) -> Retrying<T> {
+    Retrying { inner, options }
+  }
+}
+
+impl<T: Transport> Transport for Retrying<T> {
+  fn send(&self, request: &mut HttpRequest) -> Result<HttpResponse> {
+    let mut attempt = 0;
+    loop {
+      attempt += 1;
+      let result = self.inner.send(request);
+
+      let idempotent = request.method == "GET";
+      let wait = match &result {
+        Ok(response) if response.status == 429 => Some(
+          retry_after(response).map_or_else(|| self.options.backoff(attempt - 1), |w| w.min(self.options.max_backoff)),
+        ),
+        Ok(response) if response.status >= 500 && idempotent => Some(self.options.backoff(attempt - 1)),
+        Ok(_) => None,
+        Err(Error::Network(err)) if idempotent || never_sent(err) => Some(self.options.backoff(attempt - 1)),
+        #[cfg(any(feature = "async", feature = "blocking-reqwest"))]
+        Err(Error::AsyncNetwork(err)) if idempotent || err.is_connect() => Some(self.options.backoff(attempt - 1)),
+        Err(_) => None,
+      };
+
+      match wait {
+        Some(wait) if attempt < self.options.max_attempts => std::thread::sleep(wait),
+        _ => return result,
+      }
+    }
+  }
+}
+
+/* Whether the request failed before any of it reached the API. */
+fn never_sent(err: &ureq::Error) -> bool {
+  match err {
+    ureq::Error::HostNotFound | ureq::Error::ConnectionFailed => true,
+    ureq::Error::Timeout(timeout) => matches!(timeout, ureq::Timeout::Resolve | ureq::Timeout::Connect),
+    ureq::Error::Io(io) => io.kind() == std::io::ErrorKind::ConnectionRefused,
+    _ => false,
+  }
+}
+
+/* Retry-After is either a number of seconds or an HTTP date. */
+fn retry_after(response: &HttpResponse) -> Option<Duration> {
+  let value = response.header("Retry-After")?.trim();
+  if let Ok(seconds) = value.parse::<u64>() {
+    return Some(Duration::from_secs(seconds));
+  }
+  let date = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+  (date.with_timezone(&chrono::Utc) - chrono::Utc::now()).to_std().ok().or(Some(Duration::from_secs(0)))
+}
+
+/* A request and its response, as stored in fixture files.
+ * Only the path is kept from the url, so fixtures replay against any api_url.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Exchange {
+  pub method: String,
+  pub path: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub request_body: Option<serde_json::Value>,
+  pub status: u16,
+  #[serde(default)]
+  pub headers: Vec<(String, String)>,
+  pub body: String,
+}
+
+fn url_path(url: &str) -> String {
+  let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+  match without_scheme.find('/') {
+    Some(start) => without_scheme[start..].to_string(),
+    None => "/".to_string(),
+  }
+}
+
+/* Sends requests through another transport and keeps every exchange, to save them as fixtures.
+ * Response bodies are read in full, and non UTF-8 bodies are stored base64 encoded
+ * with an "X-Recorded-Base64" header.
+ */
+pub struct Recorder<T> {
+  inner: T,
+  exchanges: Mutex<Vec<Exchange>>,
+}
+
+impl<T: Transport> Recorder<T> {
+  pub fn new(inner: T) -> Recorder<T> {
+    Recorder { inner, exchanges: Mutex::new(vec![]) }
+  }
+
+  pub fn exchanges(&self) -> Vec<Exchange> {
+    self.exchanges.lock().unwrap().clone()
+  }
+
+  pub fn save(&self, path: &str) -> Result<()> {
+    std::fs::write(path, serde_json::to_string_pretty(&self.exchanges())?)?;
+    Ok(())
+  }
+}
+
+impl<T: Transport> Transport for Recorder<T> {
+  fn send(&self, request: &mut HttpRequest) -> Result<HttpResponse> {
+    let response = self.inner.send(request)?;
+    let (status, headers) = (response.status, response.headers.clone());
+    let bytes = response.into_bytes()?;
+
+    let mut recorded_headers = headers.clone();
+    let body = match String::from_utf8(bytes.clone()) {
+      Ok(text) => text,
+      Err(_) => {
+        recorded_headers.push((BASE64_MARKER.to_string(), "true".to_string()));
+        base64::encode(&bytes)
+      },
+    };
+
+    self.exchanges.lock().unwrap().push(Exchange {
+      method: request.method.to_string(),
+      path: url_path(&request.url),
+      request_body: match &request.body {
+        Body::Json(value) => Some(value.clone()),
+        _ => None,
+      },
+      status,
+      headers: recorded_headers,
+      body,
+    });
+
+    Ok(HttpResponse::new(status, headers, Cursor::new(bytes)))
+  }
+}
+
+const BASE64_MARKER: &str = "X-Recorded-Base64";
+
+/* Answers requests from recorded exchanges, without a network.
+ * Each exchange is used once, in the order they were recorded for each method and path.
+ */
+pub struct Replayer {
+  exchanges: Mutex<VecDeque<Exchange>>,
+}
+
+impl Replayer {
+  pub fn new(exchanges: Vec<Exchange>) -> Replayer {
+    Replayer { exchanges: Mutex::new(exchanges.into()) }
+  }
+
+  pub fn load(path: &str) -> Result<Replayer> {
+    Ok(Self::new(serde_json::from_str(&std::fs::read_to_string(path)?)?))
+  }
+
+  pub fn remaining(&self) -> usize {
+    self.exchanges.lock().unwrap().len()
+  }
+}
+
+impl Transport for Replayer {
+  fn send(&self, request: &mut HttpRequest) -> Result<HttpResponse> {
+    let path = url_path(&request.url);
+    let mut exchanges = self.exchanges.lock().unwrap();
+    let position = exchanges
+      .iter()
+      .position(|e| e.method == request.method && e.path == path)
+      .ok_or_else(|| Error::NoRecordedResponse(format!("{} {}", request.method, path)))?;
+    let exchange = exchanges.remove(position).expect("position to be in range");
+
+    let body = if exchange.headers.iter().any(|(name, _)| name == BASE64_MARKER) {
+      base64::decode(&exchange.body).map_err(|_| Error::NoRecordedResponse(format!("{} {}", request.method, path)))?
+    } else {
+      exchange.body.into_bytes()
+    };
+
+    let headers = exchange.headers.into_iter().filter(|(name, _)| name != BASE64_MARKER).collect();
+    Ok(HttpResponse::new(exchange.status, headers, Cursor::new(body)))
+  }
+}
+
+impl Client {
+  pub fn transport(&self) -> &dyn Transport {
+    self.transport.as_ref()
+  }
+
+  pub fn set_transport<T: Transport + 'static>(&mut self, transport: T) {
+    self.transport = Box::new(transport);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use mockito;
+
+  fn fast_transport() -> Box<dyn Transport> {
+    Box::new(http(TransportOptions {
+      initial_backoff: Duration::from_millis(1),
+      max_backoff: Duration::from_millis(5),
+      ..Default::default()
+    }))
+  }
+
+  #[test]
+  fn retries_server_errors_with_backoff() {
+    let failing = mockito::mock("GET", "/transport_retries")
+        .with_status(503)
+        .expect(2)
+        .create();
+    let working = mockito::mock("GET", "/transport_retries")
+        .with_status(200)
+        .with_body("ok")
+        .expect(1)
+        .create();
+
+    let response = fast_transport().get(&format!("{}/transport_retries", mockito::server_url()), &[]).unwrap();
+    assert_eq!(response.into_string().unwrap(), "ok");
+
+    failing.assert();
+    working.assert();
+  }
+
+  #[test]
+  fn detects_bodies_shorter_than_their_content_length() {
+    let headers = || vec![("content-length".to_string(), "10".to_string())];
+
+    let short = HttpResponse::new(200, headers(), std::io::Cursor::new(b"abc".to_vec()));
+    assert!(matches!(short.into_bytes(), Err(Error::Truncated { expected: 10, received: 3 })));
+
+    let mut progress = vec![];
+    let complete = HttpResponse::new(200, headers(), std::io::Cursor::new(b"0123456789".to_vec()));
+    assert_eq!(complete.into_bytes_with(|done, total| progress.push((done, total))).unwrap(), b"0123456789");
+    assert_eq!(progress, vec![(10, Some(10))]);
+  }
+
+  #[test]
+  fn honours_retry_after_and_gives_up_eventually() {
+    let limited = mockito::mock("GET", "/transport_limited")
+        .with_status(429)
+        .with_header("Retry-After", "0")
+        .expect(4)
+        .create();
+
+    let result = fast_transport().get(&format!("{}/transport_limited", mockito::server_url()), &[]);
+    assert!(matches!(result, Err(Error::Api { status: 429, .. })));
+
+    limited.assert();
+  }
+
+  #[test]
+  fn does_not_repeat_posts_the_api_may_have_stored() {
+    let failing = mockito::mock("POST", "/transport_post")
+        .with_status(503)
+        .expect(1)
+        .create();
+
+    let result = fast_transport().post_json(&format!("{}/transport_post", mockito::server_url()), &serde_json::json!({}));
+    assert!(matches!(result, Err(Error::Api { status: 503, .. })));
+
+    failing.assert();
+  }
+
+  #[test]
+  fn caps_retry_after_at_the_maximum_backoff() {
+    let limited = mockito::mock("POST", "/transport_post_limited")
+        .with_status(429)
+        .with_header("Retry-After", "3600")
+        .expect(4)
+        .create();
+
+    let started = std::time::Instant::now();
+    let result = fast_transport().post_json(&format!("{}/transport_post_limited", mockito::server_url()), &serde_json::json!({}));
+    assert!(matches!(result, Err(Error::Api { status: 429, .. })));
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    limited.assert();
+  }
+
+  #[test]
+  fn does_not_retry_client_errors() {
+    let missing = mockito::mock("GET", "/transport_missing")
+        .with_status(404)
+        .expect(1)
+        .create();
+
+    let result = fast_transport().get(&format!("{}/transport_missing", mockito::server_url()), &[]);
+    assert!(matches!(result, Err(Error::Api { status: 404, .. })));
+
+    missing.assert();
+  }
+
+  #[test]
+  fn backoff_grows_up_to_the_maximum() {
+    let options = TransportOptions::default();
+    assert!(options.backoff(0) <= Duration::from_millis(500));
+    assert!(options.backoff(3) >= Duration::from_secs(2));
+    assert!(options.backoff(20) <= Duration::from_secs(30));
+  }
+
+  #[test]
+  fn replays_recorded_exchanges_without_a_server() {
+    let mock = mockito::mock("GET", "/account_state")
+        .with_status(200)
+        .with_body(r#"{"missing": "1", "parked_count": 1, "person_id": 19, "token_balance": "0", "total_document_count": 367}"#)
+        .expect(1)
+        .create();
+
+    let (config, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    let signature = Signature::load(config.clone(), "not_so_secret").unwrap();
+    let recorder = std::sync::Arc::new(Recorder::new(UreqTransport::from_options(&Default::default())));
+    let client = Client { signature, api_url: mockito::server_url(), ledger: None, transport: Box::new(recorder.clone()) };
+    let recorded = client.account_state().unwrap();
+    mock.assert();
+
+    let exchanges = recorder.exchanges();
+    assert_eq!(exchanges.len(), 1);
+    assert_eq!(exchanges[0].path, "/account_state");
+
+    let signature = Signature::load(config, "not_so_secret").unwrap();
+    let replayer = Replayer::new(exchanges);
+    let client = Client { signature, api_url: "http://offline.invalid".to_string(), ledger: None, transport: Box::new(replayer) };
+    assert_eq!(client.account_state().unwrap(), recorded);
+    assert!(matches!(client.account_state(), Err(Error::NoRecordedResponse(_))));
+  }
+}
@@ -0,0 +1,199 @@
+use crate::digest::DigestStatement;
+use crate::signed_payload::{hexdigest, SignedPayload};
+use bitcoin::{Address, PublicKey};
+use serde::Serialize;
+
+/* Checks a local file against a SignedPayload without talking to the API.
+ * The payload is either the file itself, or a DigestStatement about it for hash only stamps.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Verification {
+  pub sha256: String,
+  pub hash_only: bool,
+  pub hash_matches: bool,
+  pub signature_valid: bool,
+  pub signer: Address,
+  pub signer_matches: Option<bool>,
+}
+
+impl Verification {
+  pub fn is_ok(&self) -> bool {
+    self.hash_matches && self.signature_valid && self.signer_matches.unwrap_or(true)
+  }
+}
+
+pub fn verify_signed_payload(
+  bytes: &[u8],
+  signed_payload: &SignedPayload,
+  expected_signer: Option<&PublicKey>,
+) -> Verification {
+  let sha256 = hexdigest(bytes);
+  let statement = DigestStatement::from_payload(&signed_payload.payload);
+
+  Verification {
+    hash_only: statement.is_some(),
+    hash_matches: match &statement {
+      Some(statement) => statement.matches(bytes),
+      None => sha256 == signed_payload.payload_hash(),
+    },
+    sha256,
+    signature_valid: signed_payload.signed_ok().unwrap_or(false),
+    signer: signed_payload.signer.clone(),
+    signer_matches: expected_signer
+      .map(|key| Address::p2pkh(key, signed_payload.signer.network) == signed_payload.signer),
+  }
+}
+
+/* Whether the file is one of the parts covered by a Constata HTML proof.
+ * This only reads the proof's part list, it doesn't check its Bitcoin timestamp.
+ */
+pub fn proof_references(bytes: &[u8], proof_html: &str) -> bool {
+  part_digests(proof_html).contains(&hexdigest(bytes))
+}
+
+/* Proofs list the sha256 of every part in its own element or attribute.
+ * Only values that are a whole digest count: one quoted in running text,
+ * a comment or a longer string is a mention, not a part of the document.
+ */
+pub fn part_digests(proof_html: &str) -> Vec<String> {
+  let mut values = vec![];
+  let mut rest = proof_html;
+
+  while let Some(start) = rest.find('<') {
+    values.push(&rest[..start]);
+    rest = &rest[start..];
+
+    if rest.starts_with("<!--") {
+      rest = rest.find("-->").map_or("", |end| &rest[end + 3..]);
+      continue;
+    }
+
+    let end = tag_end(rest);
+    values.extend(attribute_values(&rest[1..end]));
+    rest = rest.get(end + 1..).unwrap_or("");
+  }
+  values.push(rest);
+
+  let mut digests: Vec<String> = vec![];
+  for value in values {
+    let value = value.trim();
+    if value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit()) && !digests.iter().any(|d| d.eq_ignore_ascii_case(value)) {
+      digests.push(value.to_ascii_lowercase());
+    }
+  }
+  digests
+}
+
+/* Position of the '>' closing the tag at the start of `html`, skipping quoted values. */
+fn tag_end(html: &str) -> usize {
+  let mut quote = None;
+  for (i, c) in html.char_indices() {
+    match (quote, c) {
+      (None, '"') | (None, '\'') => quote = Some(c),
+      (Some(q), c) if q == c => quote = None,
+      (None, '>') => return i,
+      _ => {},
+    }
+  }
+  html.len()
+}
+
+fn attribute_values(tag: &str) -> Vec<&str> {
+  let mut values = vec![];
+  let mut rest = tag;
+  while let Some(eq) = rest.find('=') {
+    rest = rest[eq + 1..].trim_start();
+    let (value, after) = match rest.chars().next() {
+      Some(q) if q == '"' || q == '\'' => match rest[1..].find(q) {
+        Some(end) => (&rest[1..end + 1], &rest[end + 2..]),
+        None => (&rest[1..], ""),
+      },
+      _ => {
+        let end = rest.find(|c: char| c.is_whitespace() || c == '/').unwrap_or(rest.len());
+        (&rest[..end], &rest[end..])
+      },
+    };
+    values.push(value);
+    rest = after;
+  }
+  values
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::signature::Signature;
+
+  #[test]
+  fn verifies_a_file_against_its_signed_payload() {
+    let (config, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    let public_key = config.public_key();
+    let signature = Signature::load(config, "not_so_secret").unwrap();
+    let signed_payload = signature.sign_message(b"diploma");
+
+    let verification = verify_signed_payload(b"diploma", &signed_payload, Some(&public_key));
+    assert!(verification.is_ok());
+    assert_eq!(verification.signer_matches, Some(true));
+
+    assert!(!verify_signed_payload(b"diplomb", &signed_payload, None).is_ok());
+
+    let (other, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    let verification = verify_signed_payload(b"diploma", &signed_payload, Some(&other.public_key()));
+    assert_eq!(verification.signer_matches, Some(false));
+    assert!(!verification.is_ok());
+  }
+
+  #[test]
+  fn detects_forged_signatures() {
+    let signed_payload: SignedPayload = serde_json::from_str(r#"{
+      "payload":"bGVsbG8gd29ybGA=",
+      "signer":"mqwpxxvfv3QbM8PU8uBx2jaNt9btQqvQNx",
+      "signature":"H6O6iC1NL18vjMVllny5oQz87Ir7O6n0v/rup8zBPjjAXWENMkJRcEQ69SRKXfw2QYen2PLt3amkY2bE+Fw623w="
+    }"#).unwrap();
+    let verification = verify_signed_payload(&signed_payload.payload.clone(), &signed_payload, None);
+    assert!(verification.hash_matches);
+    assert!(!verification.signature_valid);
+  }
+
+  #[test]
+  fn verifies_a_file_against_its_digest_statement() {
+    let (config, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    let signature = Signature::load(config, "not_so_secret").unwrap();
+    let statement = DigestStatement::new("diploma.txt", b"diploma", Default::default());
+    let signed_payload = signature.sign_message(&statement.to_canonical_json());
+
+    let verification = verify_signed_payload(b"diploma", &signed_payload, None);
+    assert!(verification.hash_only);
+    assert!(verification.is_ok());
+    assert!(!verify_signed_payload(b"diplomb", &signed_payload, None).is_ok());
+  }
+
+  #[test]
+  fn checks_the_file_is_a_part_of_the_proof() {
+    let proof = format!("<html><td>{}</td></html>", hexdigest(b"diploma"));
+    assert!(proof_references(b"diploma", &proof));
+    assert!(!proof_references(b"other", &proof));
+
+    let proof = format!(
+      "<html><div class='part' data-hash=\"{}\"><a href='x?a=b'>annex.png</a></div><td>\n  {}\n</td></html>",
+      hexdigest(b"annex").to_uppercase(),
+      hexdigest(b"contract"),
+    );
+    assert_eq!(part_digests(&proof), vec![hexdigest(b"annex"), hexdigest(b"contract")]);
+    assert!(proof_references(b"annex", &proof));
+  }
+
+  #[test]
+  fn ignores_hashes_that_are_only_mentioned() {
+    let hash = hexdigest(b"diploma");
+    let mentions = vec![
+      format!("<html><p>The file {} was not stamped.</p></html>", hash),
+      format!("<html><!-- {} --><td>nothing</td></html>", hash),
+      format!("<html><a href=\"https://example.com/{}\">link</a></html>", hash),
+      format!("<html><td>{}00</td></html>", hash),
+    ];
+    for proof in mentions {
+      assert!(!proof_references(b"diploma", &proof), "{}", proof);
+    }
+  }
+}
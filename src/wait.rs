@@ -0,0 +1,130 @@
+use super::*;
+use std::time::{Duration, Instant};
+
+/* How often to poll while waiting. The interval doubles after each poll. */
+#[derive(Debug, Clone)]
+pub struct Backoff {
+  pub initial: Duration,
+  pub max: Duration,
+}
+
+impl Default for Backoff {
+  fn default() -> Self {
+    Backoff { initial: Duration::from_secs(5), max: Duration::from_secs(300) }
+  }
+}
+
+impl DocumentBundle {
+  /* A document is anchored once its bulletin is published in a Bitcoin block. */
+  pub fn is_anchored(&self) -> bool {
+    self.bulletin().is_some_and(|b| {
+      b.state == BulletinState::Published && b.transaction_hash.is_some() && b.block_time.is_some()
+    })
+  }
+}
+
+impl Client {
+  pub fn wait_for_bulletin(&self, document_id: &str, timeout: Duration) -> Result<DocumentBundle> {
+    self.wait_for_bulletin_with(document_id, timeout, &Backoff::default(), |_| {})
+  }
+
+  /* Polls the document until it's anchored or the timeout elapses.
+   * Timeouts too long to add to the current time, like Duration::MAX, never elapse.
+   * `on_change` receives the document every time its state or its bulletin's state changes.
+   */
+  pub fn wait_for_bulletin_with<F: FnMut(&DocumentBundle)>(
+    &self,
+    document_id: &str,
+    timeout: Duration,
+    backoff: &Backoff,
+    mut on_change: F,
+  ) -> Result<DocumentBundle> {
+    let deadline = Instant::now().checked_add(timeout);
+    let mut interval = backoff.initial;
+    let mut last_seen = None;
+
+    loop {
+      let document = self.document(document_id)?;
+      let seen = (document.state, document.bulletin().map(|b| b.state));
+      if last_seen != Some(seen) {
+        on_change(&document);
+        last_seen = Some(seen);
+      }
+
+      if document.is_anchored() {
+        return Ok(document);
+      }
+
+      let left = match deadline {
+        Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+          Some(left) if !left.is_zero() => left,
+          _ => return Err(Error::NotYetConfirmed { document_id: document_id.to_string() }),
+        },
+        None => interval,
+      };
+
+      std::thread::sleep(interval.min(left));
+      interval = interval.checked_mul(2).unwrap_or(backoff.max).min(backoff.max);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use mockito;
+
+  fn test_client() -> Client {
+    let (config, _mnemonic) = Signature::create("production", "very_secret", "not_so_secret").unwrap();
+    let signature = Signature::load(config, "not_so_secret").unwrap();
+    Client { signature, api_url: mockito::server_url(), ledger: None, transport: transport::default_transport() }
+  }
+
+  fn fast() -> Backoff {
+    Backoff { initial: Duration::from_millis(10), max: Duration::from_millis(20) }
+  }
+
+  #[test]
+  fn returns_once_the_bulletin_is_anchored() {
+    let client = test_client();
+    let mock = mockito::mock("GET", "/documents/1-2")
+        .with_status(200)
+        .with_body(r#"{"state":"Published","id":"1-2","person_id":1,"bulletin_id":3,"parts":[],"created_at":"2022-01-05T08:04:47.166681Z","cost":"1","gift_id":null,"bulletins":{"3":{"id":3,"state":"Published","started_at":"2022-01-05T08:04:47.166681Z","hash":"aa","transaction":"bb","transaction_hash":"cc","block_hash":"dd","block_time":"2022-01-05T09:00:00Z"}},"buy_tokens_link":null}"#)
+        .expect(1)
+        .create();
+
+    let mut changes = 0;
+    let document = client.wait_for_bulletin_with("1-2", Duration::from_secs(1), &fast(), |_| changes += 1).unwrap();
+    assert!(document.is_anchored());
+    assert_eq!(changes, 1);
+    mock.assert();
+  }
+
+  #[test]
+  fn times_out_while_parked() {
+    let client = test_client();
+    let _mock = mockito::mock("GET", "/documents/1-3")
+        .with_status(200)
+        .with_body(r#"{"state":"Parked","id":"1-3","person_id":1,"bulletin_id":null,"parts":[],"created_at":"2022-01-05T08:04:47.166681Z","cost":"1","gift_id":null,"bulletins":{},"buy_tokens_link":null}"#)
+        .create();
+
+    let mut changes = 0;
+    let result = client.wait_for_bulletin_with("1-3", Duration::from_millis(50), &fast(), |_| changes += 1);
+    assert!(matches!(result, Err(Error::NotYetConfirmed { .. })));
+    assert_eq!(changes, 1);
+  }
+
+  #[test]
+  fn waits_without_a_deadline_when_the_timeout_is_too_long() {
+    let client = test_client();
+    let mock = mockito::mock("GET", "/documents/1-4")
+        .with_status(200)
+        .with_body(r#"{"state":"Published","id":"1-4","person_id":1,"bulletin_id":3,"parts":[],"created_at":"2022-01-05T08:04:47.166681Z","cost":"1","gift_id":null,"bulletins":{"3":{"id":3,"state":"Published","started_at":"2022-01-05T08:04:47.166681Z","hash":"aa","transaction":"bb","transaction_hash":"cc","block_hash":"dd","block_time":"2022-01-05T09:00:00Z"}},"buy_tokens_link":null}"#)
+        .expect(1)
+        .create();
+
+    let document = client.wait_for_bulletin_with("1-4", Duration::MAX, &fast(), |_| {}).unwrap();
+    assert!(document.is_anchored());
+    mock.assert();
+  }
+}